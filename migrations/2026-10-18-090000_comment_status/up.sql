@@ -0,0 +1 @@
+ALTER TABLE comment ADD COLUMN status TEXT NOT NULL DEFAULT 'pending';
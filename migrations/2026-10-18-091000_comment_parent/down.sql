@@ -0,0 +1 @@
+ALTER TABLE comment DROP COLUMN parent_id;
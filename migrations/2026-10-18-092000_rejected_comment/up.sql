@@ -0,0 +1,10 @@
+CREATE TABLE rejected_comment(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  page_id INTEGER NOT NULL,
+  iso_time TEXT NOT NULL,
+  ip TEXT NOT NULL,
+  alias TEXT NOT NULL,
+  text TEXT NOT NULL,
+  reason TEXT NOT NULL,
+  FOREIGN KEY (page_id) REFERENCES page(id) ON DELETE CASCADE
+)
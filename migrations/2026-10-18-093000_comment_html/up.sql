@@ -0,0 +1,3 @@
+ALTER TABLE comment ADD COLUMN html_content TEXT NOT NULL DEFAULT '';
+-- existing comments were plain text, so carry them over escaped
+UPDATE comment SET html_content = '<p>' || replace(replace(replace(replace(text, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '"', '&quot;') || '</p>';
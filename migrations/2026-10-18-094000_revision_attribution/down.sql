@@ -0,0 +1,3 @@
+ALTER TABLE page_revision DROP COLUMN is_minor;
+ALTER TABLE page_revision DROP COLUMN summary;
+ALTER TABLE page_revision DROP COLUMN admin_id;
@@ -0,0 +1,3 @@
+ALTER TABLE page_revision ADD COLUMN admin_id INTEGER REFERENCES admin(id);
+ALTER TABLE page_revision ADD COLUMN summary TEXT NOT NULL DEFAULT '';
+ALTER TABLE page_revision ADD COLUMN is_minor BOOLEAN NOT NULL DEFAULT 0;
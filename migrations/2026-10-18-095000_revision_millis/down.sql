@@ -0,0 +1,21 @@
+CREATE TABLE page_revision_old(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  page_id INTEGER,
+  iso_time TEXT NOT NULL,
+  unix_time INTEGER NOT NULL,
+  html_content TEXT NOT NULL,
+  markdown_content TEXT NOT NULL,
+  sidebar_html_content TEXT NOT NULL,
+  sidebar_markdown_content TEXT NOT NULL,
+  admin_id INTEGER REFERENCES admin(id),
+  summary TEXT NOT NULL DEFAULT '',
+  is_minor BOOLEAN NOT NULL DEFAULT 0,
+  FOREIGN KEY (page_id) REFERENCES page(id) ON DELETE CASCADE
+);
+
+INSERT INTO page_revision_old (id, page_id, iso_time, unix_time, html_content, markdown_content, sidebar_html_content, sidebar_markdown_content, admin_id, summary, is_minor)
+SELECT id, page_id, strftime('%Y-%m-%d', unix_time / 1000, 'unixepoch'), unix_time / 1000, html_content, markdown_content, sidebar_html_content, sidebar_markdown_content, admin_id, summary, is_minor
+FROM page_revision;
+
+DROP TABLE page_revision;
+ALTER TABLE page_revision_old RENAME TO page_revision;
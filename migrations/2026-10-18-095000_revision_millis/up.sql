@@ -0,0 +1,25 @@
+-- SQLite cannot change a column's type in place, so rebuild the table with a 64-bit
+-- millisecond timestamp and full ISO-8601 datetimes.
+CREATE TABLE page_revision_new(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  page_id INTEGER,
+  iso_time TEXT NOT NULL,
+  unix_time BIGINT NOT NULL,
+  html_content TEXT NOT NULL,
+  markdown_content TEXT NOT NULL,
+  sidebar_html_content TEXT NOT NULL,
+  sidebar_markdown_content TEXT NOT NULL,
+  admin_id INTEGER REFERENCES admin(id),
+  summary TEXT NOT NULL DEFAULT '',
+  is_minor BOOLEAN NOT NULL DEFAULT 0,
+  FOREIGN KEY (page_id) REFERENCES page(id) ON DELETE CASCADE
+);
+
+INSERT INTO page_revision_new (id, page_id, iso_time, unix_time, html_content, markdown_content, sidebar_html_content, sidebar_markdown_content, admin_id, summary, is_minor)
+SELECT id, page_id, strftime('%Y-%m-%dT%H:%M:%fZ', unix_time, 'unixepoch'), unix_time * 1000, html_content, markdown_content, sidebar_html_content, sidebar_markdown_content, admin_id, summary, is_minor
+FROM page_revision;
+
+DROP TABLE page_revision;
+ALTER TABLE page_revision_new RENAME TO page_revision;
+
+CREATE INDEX page_revision_page_id_unix_time ON page_revision (page_id, unix_time);
@@ -0,0 +1 @@
+ALTER TABLE page_revision DROP COLUMN is_delta;
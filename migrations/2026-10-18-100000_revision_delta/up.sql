@@ -0,0 +1,2 @@
+-- when set, the content columns hold line deltas against the next newer revision
+ALTER TABLE page_revision ADD COLUMN is_delta BOOLEAN NOT NULL DEFAULT 0;
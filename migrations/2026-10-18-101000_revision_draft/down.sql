@@ -0,0 +1,2 @@
+ALTER TABLE page_revision DROP COLUMN publish_at;
+ALTER TABLE page_revision DROP COLUMN is_draft;
@@ -0,0 +1,4 @@
+-- drafts are only shown to admins, and are published on their own once publish_at
+-- (unix milliseconds) has passed, if it is set
+ALTER TABLE page_revision ADD COLUMN is_draft BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE page_revision ADD COLUMN publish_at BIGINT;
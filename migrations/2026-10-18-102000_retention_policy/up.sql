@@ -0,0 +1,9 @@
+-- page_id is the root of the subtree the policy covers, or NULL for the global policy
+CREATE TABLE retention_policy(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  page_id INTEGER,
+  keep_all_days INTEGER NOT NULL,
+  keep_weekly_days INTEGER NOT NULL,
+  keep_monthly_days INTEGER,
+  FOREIGN KEY (page_id) REFERENCES page(id) ON DELETE CASCADE
+)
@@ -0,0 +1,2 @@
+ALTER TABLE page DROP COLUMN trash_root_id;
+ALTER TABLE page DROP COLUMN deleted_at;
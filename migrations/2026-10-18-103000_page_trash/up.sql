@@ -0,0 +1,3 @@
+-- set on every page of a deleted subtree; trash_root_id is the page the delete was made on
+ALTER TABLE page ADD COLUMN deleted_at BIGINT;
+ALTER TABLE page ADD COLUMN trash_root_id INTEGER;
@@ -0,0 +1,7 @@
+-- paths pages were at before being renamed or moved, kept so that old links redirect
+CREATE TABLE page_redirect(
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  old_path TEXT NOT NULL UNIQUE,
+  page_id INTEGER NOT NULL,
+  FOREIGN KEY (page_id) REFERENCES page(id) ON DELETE CASCADE
+)
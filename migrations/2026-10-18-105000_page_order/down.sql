@@ -0,0 +1,2 @@
+ALTER TABLE page DROP COLUMN child_sort;
+ALTER TABLE page DROP COLUMN position;
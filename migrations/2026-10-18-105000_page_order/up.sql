@@ -0,0 +1,4 @@
+-- position orders a page among its siblings when its parent's child_sort is 'manual'
+ALTER TABLE page ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE page ADD COLUMN child_sort TEXT NOT NULL DEFAULT 'manual';
+UPDATE page SET position = id;
@@ -0,0 +1,2 @@
+DROP INDEX page_path;
+ALTER TABLE page DROP COLUMN path;
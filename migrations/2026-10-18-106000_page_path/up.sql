@@ -0,0 +1,15 @@
+-- path holds the slugs from the root down, joined as '/a/b' (the root's is ''), so that
+-- pages can be found by path without walking parent_id
+ALTER TABLE page ADD COLUMN path TEXT NOT NULL DEFAULT '';
+WITH RECURSIVE CTE AS (
+  SELECT id, slug AS path
+  FROM page
+  WHERE parent_id IS NULL
+  UNION ALL
+  SELECT p.id, CTE.path || '/' || p.slug
+  FROM page p
+  JOIN CTE ON p.parent_id = CTE.id
+)
+UPDATE page SET path = (SELECT CTE.path FROM CTE WHERE CTE.id = page.id)
+WHERE id IN (SELECT id FROM CTE);
+CREATE INDEX page_path ON page (path);
@@ -0,0 +1 @@
+ALTER TABLE page DROP COLUMN show_child_index;
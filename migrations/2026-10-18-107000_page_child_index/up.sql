@@ -0,0 +1,3 @@
+-- show_child_index lists a page's children below its content, as the [[children]]
+-- placeholder does wherever it is put in the markdown
+ALTER TABLE page ADD COLUMN show_child_index BOOLEAN NOT NULL DEFAULT 0;
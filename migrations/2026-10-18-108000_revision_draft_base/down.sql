@@ -0,0 +1,2 @@
+ALTER TABLE page_revision DROP COLUMN publish_conflict;
+ALTER TABLE page_revision DROP COLUMN base_revision_id;
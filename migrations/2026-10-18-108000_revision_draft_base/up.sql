@@ -0,0 +1,5 @@
+-- base_revision_id is the published revision a draft was written against; a draft whose
+-- base is no longer the latest is merged when published, and publish_conflict flags one
+-- that could not be merged cleanly and was left unpublished
+ALTER TABLE page_revision ADD COLUMN base_revision_id INTEGER;
+ALTER TABLE page_revision ADD COLUMN publish_conflict BOOLEAN NOT NULL DEFAULT 0;
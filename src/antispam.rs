@@ -0,0 +1,223 @@
+use crate::{models::CommentRejection, views::comments::CommentInfo, ManagedState};
+use crypto::{
+    digest::Digest,
+    hmac::Hmac,
+    mac::Mac,
+    sha2::Sha256,
+    util::fixed_time_eq,
+};
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
+    hash::{BuildHasher, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How many used challenges are remembered at most. Comments are rate limited, so this
+/// only has to cover the ones made within `max_age`.
+const SPENT_CHALLENGES_CAPACITY: usize = 4096;
+
+/// Challenges handed out with comment forms, in a hidden field. Each one is
+/// `issued_at:nonce:signature`, so nothing is kept per served form; it doubles as the
+/// proof-of-work challenge, and is good for one comment within `max_age`.
+pub struct ChallengeSigner {
+    /// Random per process, so that challenges can't be forged or worked out in advance.
+    key: [u8; 32],
+    counter: AtomicU64,
+    max_age_millis: i64,
+    spent: Mutex<SpentChallenges>,
+}
+
+/// Challenges already used for a comment, oldest first.
+#[derive(Default)]
+struct SpentChallenges {
+    order: VecDeque<(i64, String)>,
+    challenges: HashSet<String>,
+}
+
+impl ChallengeSigner {
+    pub fn new(max_age: Duration) -> Self {
+        let mut key = [0u8; 32];
+        for chunk in key.chunks_mut(8) {
+            chunk.copy_from_slice(&RandomState::new().build_hasher().finish().to_le_bytes());
+        }
+
+        ChallengeSigner {
+            key: key,
+            counter: AtomicU64::new(0),
+            max_age_millis: max_age.as_millis() as i64,
+            spent: Mutex::new(SpentChallenges::default()),
+        }
+    }
+
+    fn sign(&self, payload: &str) -> String {
+        let mut hmac = Hmac::new(Sha256::new(), &self.key);
+        hmac.input(payload.as_bytes());
+        hmac.result()
+            .code()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Issues a challenge for a comment form served at `now` (unix millis).
+    pub fn issue(&self, now: i64) -> String {
+        let payload = format!("{}:{}", now, self.counter.fetch_add(1, Ordering::Relaxed));
+        let signature = self.sign(&payload);
+        format!("{}:{}", payload, signature)
+    }
+
+    /// When the form with `challenge` was served, if it was signed here, is not too old and
+    /// has not been used yet.
+    fn issued_at(&self, challenge: &str, now: i64) -> Option<i64> {
+        let (payload, signature) = challenge.rsplit_once(':')?;
+        if !fixed_time_eq(self.sign(payload).as_bytes(), signature.as_bytes()) {
+            return None;
+        }
+
+        let issued_at: i64 = payload.split(':').next()?.parse().ok()?;
+        if now - issued_at > self.max_age_millis {
+            return None;
+        }
+        if self.spent.lock().unwrap().challenges.contains(challenge) {
+            return None;
+        }
+        Some(issued_at)
+    }
+
+    /// Uses up a challenge issued at `issued_at`, returning false if it already was.
+    fn spend(&self, challenge: &str, issued_at: i64, now: i64) -> bool {
+        let mut spent = self.spent.lock().unwrap();
+
+        // expired challenges are turned away anyway, so they can be forgotten
+        while let Some((oldest_issued_at, _)) = spent.order.front() {
+            if now - *oldest_issued_at <= self.max_age_millis
+                && spent.order.len() < SPENT_CHALLENGES_CAPACITY
+            {
+                break;
+            }
+            let (_, forgotten) = spent.order.pop_front().unwrap();
+            spent.challenges.remove(&forgotten);
+        }
+
+        if !spent.challenges.insert(challenge.to_string()) {
+            return false;
+        }
+        spent.order.push_back((issued_at, challenge.to_string()));
+        true
+    }
+}
+
+/// Sliding-window limit on comment submissions per client address.
+pub struct RateLimiter {
+    submissions: Mutex<HashMap<Option<IpAddr>, VecDeque<Instant>>>,
+    limit: usize,
+    window: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        RateLimiter {
+            submissions: Mutex::new(HashMap::new()),
+            limit: limit,
+            window: window,
+        }
+    }
+
+    /// Records a submission from `ip`, returning false if it goes over the limit.
+    pub fn check(&self, ip: Option<IpAddr>) -> bool {
+        let now = Instant::now();
+        let window = self.window;
+        let mut submissions = self.submissions.lock().unwrap();
+
+        submissions.retain(|_, times| {
+            while times
+                .front()
+                .map_or(false, |time| now.duration_since(*time) > window)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        let recent = submissions.entry(ip).or_insert_with(VecDeque::new);
+        if recent.len() >= self.limit {
+            return false;
+        }
+        recent.push_back(now);
+        true
+    }
+}
+
+/// Runs every anti-spam check on a comment submission before it reaches the database.
+pub fn check_submission(
+    state: &ManagedState,
+    ip: Option<IpAddr>,
+    comment_info: &CommentInfo,
+    now: i64,
+) -> Result<(), CommentRejection> {
+    if !state.comment_rate_limiter.check(ip) {
+        return Err(CommentRejection::RateLimited);
+    }
+
+    // the honeypot field is hidden from people, but bots tend to fill in every input
+    if comment_info.website != "" {
+        return Err(CommentRejection::Honeypot);
+    }
+
+    let issued_at = match state.comment_challenges.issued_at(&comment_info.challenge, now) {
+        Some(issued_at) => issued_at,
+        None => return Err(CommentRejection::FormExpired),
+    };
+
+    if now - issued_at < state.comment_min_fill_secs * 1000 {
+        return Err(CommentRejection::TooFast);
+    }
+
+    if state.comment_pow_difficulty > 0
+        && !verify_proof_of_work(
+            &comment_info.challenge,
+            &comment_info.nonce,
+            state.comment_pow_difficulty,
+        )
+    {
+        return Err(CommentRejection::ProofOfWork);
+    }
+
+    // rejected attempts leave the challenge for another try, accepted ones can't be replayed
+    if !state
+        .comment_challenges
+        .spend(&comment_info.challenge, issued_at, now)
+    {
+        return Err(CommentRejection::FormExpired);
+    }
+
+    Ok(())
+}
+
+/// Checks that SHA-256 of `challenge:nonce` starts with `difficulty` zero bits.
+pub fn verify_proof_of_work(challenge: &str, nonce: &str, difficulty: u32) -> bool {
+    let mut hasher = Sha256::new();
+    hasher.input_str(&format!("{}:{}", challenge, nonce));
+    let mut hash = [0u8; 32];
+    hasher.result(&mut hash);
+
+    leading_zero_bits(&hash) >= difficulty
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut count = 0;
+    for byte in bytes {
+        if *byte == 0 {
+            count += 8;
+        } else {
+            count += byte.leading_zeros();
+            break;
+        }
+    }
+    count
+}
@@ -2,6 +2,7 @@
 extern crate slugify;
 extern crate rocket;
 
+use chrono_tz::Tz;
 use diesel::{
     prelude::*,
     sql_query,
@@ -11,19 +12,33 @@ use pulldown_cmark::Options;
 use rocket::{
     config::{Config, SecretKey},
     fairing::AdHoc,
-    launch, routes, State,
+    routes, Build, Rocket, State,
 };
 use rocket_dyn_templates::Template;
 use rocket_sync_db_pools::{database, diesel};
 use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
 
+mod antispam;
 mod models;
+mod nav;
 mod schema;
 mod util;
 mod views;
 
 pub struct ManagedState {
     parser_options: Options,
+    comment_parser_options: Options,
+    comment_max_depth: i32,
+    comment_rate_limiter: antispam::RateLimiter,
+    comment_min_fill_secs: i64,
+    comment_pow_difficulty: u32,
+    comment_challenges: antispam::ChallengeSigner,
+    display_timezone: Tz,
+    publish_check_interval: Duration,
+    trash_purge_days: i64,
+    /// Shared with the scheduled tasks, which purge expired trash from it.
+    nav: Arc<nav::NavTree>,
 }
 
 #[database("persist_database")]
@@ -32,19 +47,72 @@ pub struct PersistDatabase(diesel::SqliteConnection);
 #[database("memory_database")]
 pub struct MemoryDatabase(diesel::SqliteConnection);
 
-#[launch]
-async fn rocket() -> _ {
+#[rocket::main]
+async fn main() -> Result<(), rocket::Error> {
+    // `blorg compact-revisions` converts the revisions of an existing database to deltas
+    // and exits, instead of serving.
+    if std::env::args().nth(1).as_deref() == Some("compact-revisions") {
+        let rocket = rocket().ignite().await?;
+        let db = PersistDatabase::get_one(&rocket).await.unwrap();
+        let compacted = models::PageRevision::compact_all(&db).await;
+        println!("Compacted {} revisions.", compacted);
+        return Ok(());
+    }
+
+    rocket().launch().await?;
+    Ok(())
+}
+
+fn rocket() -> Rocket<Build> {
     let mut options = Options::empty();
     options.insert(Options::ENABLE_STRIKETHROUGH);
 
-    rocket::build()
+    let mut comment_options = Options::empty();
+    comment_options.insert(Options::ENABLE_STRIKETHROUGH);
+
+    let rocket = rocket::build();
+    let figment = rocket.figment();
+
+    let comment_max_depth: i32 = figment.extract_inner("comment_max_depth").unwrap_or(4);
+    let comment_rate_limit: usize = figment.extract_inner("comment_rate_limit").unwrap_or(5);
+    let comment_rate_window_secs: u64 = figment
+        .extract_inner("comment_rate_window_secs")
+        .unwrap_or(600);
+    let comment_min_fill_secs: i64 = figment.extract_inner("comment_min_fill_secs").unwrap_or(5);
+    let comment_pow_difficulty: u32 = figment.extract_inner("comment_pow_difficulty").unwrap_or(0);
+    let comment_form_max_age_secs: u64 = figment
+        .extract_inner("comment_form_max_age_secs")
+        .unwrap_or(86400);
+    let display_timezone: Tz = figment
+        .extract_inner::<String>("display_timezone")
+        .map(|name| name.parse().expect("Unknown display_timezone"))
+        .unwrap_or(Tz::UTC);
+    let publish_check_secs: u64 = figment.extract_inner("publish_check_secs").unwrap_or(60);
+    let trash_purge_days: i64 = figment.extract_inner("trash_purge_days").unwrap_or(30);
+    // 0 lists every level
+    let nav_depth: usize = figment.extract_inner("nav_depth").unwrap_or(0);
+    let nav_theme = match figment.extract_inner::<String>("nav_theme").as_deref() {
+        Ok("ascii") | Err(_) => nav::NavTheme::Ascii,
+        Ok("plain") => nav::NavTheme::Plain,
+        Ok(_) => panic!("Unknown nav_theme"),
+    };
+
+    rocket
         .mount("/", routes![views::pages::get_page])
         .mount("/", routes![views::pages::create_child_page])
         .mount("/", routes![views::pages::create_child_page_form])
         .mount("/", routes![views::pages::edit_page_form])
         .mount("/", routes![views::pages::edit_page])
         .mount("/", routes![views::pages::delete_page])
+        .mount("/", routes![views::pages::revert_page])
         .mount("/", routes![views::pages::download_page_markdown])
+        .mount("/", routes![views::pages::diff_page])
+        .mount("/", routes![views::pages::blame_page])
+        .mount("/", routes![views::pages::move_page_form])
+        .mount("/", routes![views::pages::move_page])
+        .mount("/", routes![views::pages::order_children_form])
+        .mount("/", routes![views::pages::order_children])
+        .mount("/", routes![views::pages::publish_page])
         .mount("/", routes![views::admins::upload_image])
         .mount("/", routes![views::admins::upload_image_form])
         .mount("/", routes![views::admins::admin_panel])
@@ -52,12 +120,46 @@ async fn rocket() -> _ {
         .mount("/", routes![views::admins::authenticate])
         .mount("/", routes![views::admins::deauth])
         .mount("/", routes![views::search::search_pages])
+        .mount("/", routes![views::comments::create_comment])
+        .mount("/", routes![views::comments::moderation_queue])
+        .mount("/", routes![views::comments::moderate_comments])
+        .mount("/", routes![views::comments::rejected_comments])
+        .mount("/", routes![views::retention::retention_policies])
+        .mount("/", routes![views::retention::save_retention_policy])
+        .mount("/", routes![views::retention::delete_retention_policy])
+        .mount("/", routes![views::retention::prune_preview])
+        .mount("/", routes![views::retention::prune_revisions])
+        .mount("/", routes![views::trash::trash])
+        .mount("/", routes![views::trash::restore_trashed_page])
+        .mount("/", routes![views::trash::purge_trashed_page])
         .mount("/", routes![views::files])
         .mount("/", routes![views::page_redirect])
         .manage(ManagedState {
             parser_options: options,
+            comment_parser_options: comment_options,
+            comment_max_depth: comment_max_depth,
+            comment_rate_limiter: antispam::RateLimiter::new(
+                comment_rate_limit,
+                Duration::from_secs(comment_rate_window_secs),
+            ),
+            comment_min_fill_secs: comment_min_fill_secs,
+            comment_pow_difficulty: comment_pow_difficulty,
+            comment_challenges: antispam::ChallengeSigner::new(Duration::from_secs(
+                comment_form_max_age_secs,
+            )),
+            display_timezone: display_timezone,
+            publish_check_interval: Duration::from_secs(publish_check_secs),
+            trash_purge_days: trash_purge_days,
+            nav: Arc::new(nav::NavTree::new(
+                Some(nav_depth).filter(|depth| *depth > 0),
+                nav_theme,
+            )),
         })
-        .attach(Template::fairing())
+        .attach(Template::custom(move |engines| {
+            engines
+                .tera
+                .register_filter("datetime", util::datetime_filter(display_timezone));
+        }))
         .attach(PersistDatabase::fairing())
         .attach(MemoryDatabase::fairing())
         .attach(AdHoc::on_liftoff("Init Databases", |rocket| {
@@ -66,6 +168,21 @@ async fn rocket() -> _ {
                 let memdb = MemoryDatabase::get_one(rocket).await.unwrap();
                 let state = rocket.state::<ManagedState>().unwrap();
                 init_with_defaults(&db, &memdb, state.into()).await;
+
+                // Scheduled tasks start only once the databases are set up, so the first
+                // pass never runs against missing tables.
+                let interval = state.publish_check_interval;
+                let trash_purge_days = state.trash_purge_days;
+                let parser_options = state.parser_options;
+                let nav = state.nav.clone();
+                rocket::tokio::spawn(async move {
+                    loop {
+                        models::PageRevision::publish_due(&db, &memdb, parser_options).await;
+                        models::Page::purge_expired_trash(&db, &memdb, &nav, trash_purge_days)
+                            .await;
+                        rocket::tokio::time::sleep(interval).await;
+                    }
+                });
             })
         }))
 }
@@ -81,6 +198,11 @@ async fn init_with_defaults(
 
     SearchResult::init_memory_table(memory_connection).await;
 
+    let repaired = Page::check_paths(connection).await;
+    if repaired > 0 {
+        rocket::warn!("Rebuilt {} page paths that disagreed with their parents.", repaired);
+    }
+
     let page_count: i64 = connection
         .run(move |c| page.count().get_result(c).unwrap())
         .await;
@@ -90,4 +212,6 @@ async fn init_with_defaults(
     }
 
     SearchResult::populate_with_revisions(connection, memory_connection).await;
+
+    state.nav.reload(Page::all_in_tree(connection).await);
 }
@@ -1,28 +1,38 @@
 use crate::{
-    schema::{admin, page, page_revision},
-    util::md2html,
-    views::pages::PageInfo,
+    schema::{
+        admin, comment, commenter, page, page_redirect, page_revision, rejected_comment,
+        retention_policy,
+    },
+    util::{
+        apply_delta, blame_lines, comment_md2html, encode_delta, first_paragraph, hash_password,
+        md2html, merge3, normalize_slug, parse_local_datetime, revision_timestamp,
+    },
+    nav::NavTree,
+    views::{comments::CommentInfo, pages::PageInfo},
     ManagedState, MemoryDatabase, PersistDatabase,
 };
-use chrono::Utc;
+use chrono::{TimeZone, Utc};
 use diesel::{
     prelude::*,
     sql_query,
-    sql_types::{Integer, Nullable, Text},
+    sql_types::{BigInt, Bool, Integer, Nullable, Text},
 };
+use pulldown_cmark::Options;
 use rocket::{
     outcome::IntoOutcome,
     request::{self, FromRequest, Request},
-    State,
+    FromFormField, State,
 };
 use serde::{Deserialize, Serialize};
-use slab_tree::*;
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
 
 use std::path::PathBuf;
 
 pub struct AuthenticatedAdmin {
-    id: i32,
+    pub id: i32,
 }
 
 #[rocket::async_trait]
@@ -55,8 +65,8 @@ pub struct PageRevision {
     pub page_id: Option<i32>,
     #[diesel(sql_type = Text)]
     pub iso_time: String,
-    #[diesel(sql_type = Integer)]
-    pub unix_time: i32, // HACK Diesel uses i32 for the default sqlite integer, so this fails in 2038
+    #[diesel(sql_type = BigInt)]
+    pub unix_time: i64, // milliseconds
     #[diesel(sql_type = Text)]
     pub html_content: String,
     #[diesel(sql_type = Text)]
@@ -65,69 +75,650 @@ pub struct PageRevision {
     pub sidebar_html_content: String,
     #[diesel(sql_type = Text)]
     pub sidebar_markdown_content: String,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub admin_id: Option<i32>,
+    #[diesel(sql_type = Text)]
+    pub summary: String,
+    #[diesel(sql_type = Bool)]
+    pub is_minor: bool,
+    /// Older revisions keep their content as deltas against the next newer revision; see
+    /// `PageRevision::load_all` for rebuilding them.
+    #[diesel(sql_type = Bool)]
+    pub is_delta: bool,
+    /// Drafts are only shown to admins and are kept out of the delta chain, always stored
+    /// in full.
+    #[diesel(sql_type = Bool)]
+    pub is_draft: bool,
+    /// When a draft is published on its own, in unix milliseconds.
+    #[diesel(sql_type = Nullable<BigInt>)]
+    pub publish_at: Option<i64>,
+    /// The published revision a draft was written against, merged with whatever was
+    /// published since when the draft is.
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub base_revision_id: Option<i32>,
+    /// Set on a draft that conflicted with the revisions published since its base, and so
+    /// was left unpublished.
+    #[diesel(sql_type = Bool)]
+    pub publish_conflict: bool,
+}
+
+/// A revision as listed in a page's history, with the username of the admin who made it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RevisionListing {
+    pub revision: PageRevision,
+    pub author: Option<String>,
+}
+
+/// A line of a page's current markdown, with the revision that last changed it.
+#[derive(Serialize, Debug, Clone)]
+pub struct BlameLine {
+    pub line_number: usize,
+    pub text: String,
+    pub revision_id: Option<i32>,
+    pub iso_time: String,
+    pub unix_time: i64,
+    pub author: Option<String>,
+    /// Whether the line above was changed by a different revision, so that a run of lines
+    /// from one revision is only labelled once.
+    pub starts_run: bool,
+}
+
+/// Runs `f` in a transaction that takes the write lock up front, waiting for any other
+/// writer, so that reading a page's revision chain and re-storing it can't interleave with
+/// another save.
+fn chain_transaction<T>(
+    c: &mut SqliteConnection,
+    f: impl FnOnce(&mut SqliteConnection) -> QueryResult<T>,
+) -> QueryResult<T> {
+    sql_query("PRAGMA busy_timeout = 5000").execute(c)?;
+    c.immediate_transaction(f)
 }
 
 impl PageRevision {
-    pub async fn get_nth_revision(
+    pub async fn list_for_page(
+        connection: &PersistDatabase,
+        target_page_id: i32,
+        include_drafts: bool,
+    ) -> Vec<RevisionListing> {
+        connection
+            .run(move |c| {
+                use crate::schema::{admin, page_revision::dsl::*};
+                let query = page_revision
+                    .left_join(admin::table)
+                    .filter(page_id.eq(target_page_id))
+                    .order((unix_time, id))
+                    .into_boxed();
+                let query = match include_drafts {
+                    true => query,
+                    false => query.filter(is_draft.eq(false)),
+                };
+                query
+                    .select((
+                        crate::schema::page_revision::all_columns,
+                        admin::username.nullable(),
+                    ))
+                    .load::<(PageRevision, Option<String>)>(c)
+                    .expect("Database error finding page revision")
+            })
+            .await
+            .into_iter()
+            .map(|(revision, author)| RevisionListing {
+                revision: revision,
+                author: author,
+            })
+            .collect()
+    }
+
+    /// Looks up a revision of a page by its id, or the latest published revision if none is
+    /// given.
+    pub async fn get_revision(
         connection: &PersistDatabase,
         target_page_id: i32,
-        revision: Option<usize>,
-    ) -> Self {
-        let all_revisions = connection
+        revision_id: Option<i32>,
+    ) -> Option<Self> {
+        let stored = connection
             .run(move |c| {
                 use crate::schema::page_revision::dsl::*;
-                page_revision
+                let query = page_revision
                     .filter(crate::schema::page_revision::dsl::page_id.eq(target_page_id))
-                    .order(unix_time)
-                    .load::<PageRevision>(c)
+                    .order((unix_time.desc(), id.desc()))
+                    .into_boxed();
+                let query = match revision_id {
+                    Some(rev) => query.filter(id.eq(rev)),
+                    None => query.filter(is_draft.eq(false)),
+                };
+                query
+                    .first::<PageRevision>(c)
+                    .optional()
                     .expect("Database error finding page revision")
             })
+            .await?;
+
+        if !stored.is_delta {
+            return Some(stored);
+        }
+
+        Self::load_all(connection, target_page_id)
+            .await
+            .into_iter()
+            .find(|revision| revision.id == stored.id)
+    }
+
+    /// Loads every revision of a page, oldest first, with delta-stored content rebuilt by
+    /// walking back from the latest (full) published revision.
+    pub async fn load_all(connection: &PersistDatabase, target_page_id: i32) -> Vec<Self> {
+        connection
+            .run(move |c| Self::load_all_in(c, target_page_id))
+            .await
+            .expect("Database error finding page revision")
+    }
+
+    /// `load_all` on a connection already in hand, e.g. inside a transaction.
+    fn load_all_in(c: &mut SqliteConnection, target_page_id: i32) -> QueryResult<Vec<Self>> {
+        let mut all_revisions = {
+            use crate::schema::page_revision::dsl::*;
+            page_revision
+                .filter(crate::schema::page_revision::dsl::page_id.eq(target_page_id))
+                .order((unix_time, id))
+                .load::<PageRevision>(c)?
+        };
+
+        let mut newer: Option<PageRevision> = None;
+        for index in (0..all_revisions.len()).rev() {
+            if all_revisions[index].is_draft {
+                continue;
+            }
+            if all_revisions[index].is_delta {
+                let newer = newer
+                    .as_ref()
+                    .expect("Delta revision without a newer revision");
+                let older = &mut all_revisions[index];
+                older.html_content = apply_delta(&newer.html_content, &older.html_content);
+                older.markdown_content =
+                    apply_delta(&newer.markdown_content, &older.markdown_content);
+                older.sidebar_html_content =
+                    apply_delta(&newer.sidebar_html_content, &older.sidebar_html_content);
+                older.sidebar_markdown_content =
+                    apply_delta(&newer.sidebar_markdown_content, &older.sidebar_markdown_content);
+                older.is_delta = false;
+            }
+            newer = Some(all_revisions[index].clone());
+        }
+
+        Ok(all_revisions)
+    }
+
+    /// The published revisions of a page, fully loaded, oldest first.
+    fn load_published_in(c: &mut SqliteConnection, target_page_id: i32) -> QueryResult<Vec<Self>> {
+        Ok(Self::load_all_in(c, target_page_id)?
+            .into_iter()
+            .filter(|revision| !revision.is_draft)
+            .collect())
+    }
+
+    /// Walks the published revisions of a page from the oldest, labelling each line of the
+    /// latest markdown with the revision that last changed it.
+    pub async fn blame(connection: &PersistDatabase, target_page_id: i32) -> Vec<BlameLine> {
+        let all_revisions: Vec<PageRevision> = Self::load_all(connection, target_page_id)
+            .await
+            .into_iter()
+            .filter(|revision| !revision.is_draft)
+            .collect();
+        let authors: HashMap<Option<i32>, Option<String>> =
+            Self::list_for_page(connection, target_page_id, false)
+                .await
+                .into_iter()
+                .map(|listing| (listing.revision.id, listing.author))
+                .collect();
+
+        let versions: Vec<&str> = all_revisions
+            .iter()
+            .map(|revision| revision.markdown_content.as_str())
+            .collect();
+        let owners = blame_lines(&versions);
+
+        let latest = match versions.last() {
+            Some(latest) => latest,
+            None => return Vec::new(),
+        };
+
+        latest
+            .split_inclusive('\n')
+            .zip(owners.iter())
+            .enumerate()
+            .map(|(index, (text, owner))| {
+                let revision = &all_revisions[*owner];
+                BlameLine {
+                    line_number: index + 1,
+                    text: text.trim_end_matches(&['\r', '\n'][..]).to_string(),
+                    revision_id: revision.id,
+                    iso_time: revision.iso_time.clone(),
+                    unix_time: revision.unix_time,
+                    author: authors.get(&revision.id).cloned().flatten(),
+                    starts_run: index == 0 || owners[index - 1] != *owner,
+                }
+            })
+            .collect()
+    }
+
+    /// Saves a new (full) revision, and turns the published revision it supersedes into a
+    /// delta. Drafts supersede nothing.
+    pub async fn insert(self, connection: &PersistDatabase) {
+        connection
+            .run(move |c| {
+                chain_transaction(c, |c| {
+                    let previous = match (self.page_id, self.is_draft) {
+                        (Some(target_page_id), false) => {
+                            Self::latest_published_in(c, target_page_id)?
+                        }
+                        _ => None,
+                    };
+
+                    diesel::insert_into(crate::schema::page_revision::dsl::page_revision)
+                        .values(self.clone())
+                        .execute(c)?;
+
+                    if let Some(previous) = previous {
+                        previous.store_in(Some(&self), c)?;
+                    }
+                    Ok(())
+                })
+                .expect("Error saving page revision")
+            })
             .await;
-        let to_view = match revision {
-            Some(rev) => all_revisions
-                .iter()
-                .nth(rev)
-                .expect("No such page revision found."),
-            None => all_revisions.last().expect("No such page revision found."),
+    }
+
+    /// The latest published revision of a page as stored, which is always in full.
+    fn latest_published_in(
+        c: &mut SqliteConnection,
+        target_page_id: i32,
+    ) -> QueryResult<Option<Self>> {
+        use crate::schema::page_revision::dsl::*;
+        page_revision
+            .filter(crate::schema::page_revision::dsl::page_id.eq(target_page_id))
+            .filter(is_draft.eq(false))
+            .order((unix_time.desc(), id.desc()))
+            .first::<PageRevision>(c)
+            .optional()
+    }
+
+    /// Rewrites the stored content of a (fully loaded) revision: as a delta against `newer`,
+    /// or in full if it is the latest revision.
+    fn store_in(self, newer: Option<&PageRevision>, c: &mut SqliteConnection) -> QueryResult<()> {
+        let stored = match newer {
+            Some(newer) => PageRevision {
+                html_content: encode_delta(&newer.html_content, &self.html_content),
+                markdown_content: encode_delta(&newer.markdown_content, &self.markdown_content),
+                sidebar_html_content: encode_delta(
+                    &newer.sidebar_html_content,
+                    &self.sidebar_html_content,
+                ),
+                sidebar_markdown_content: encode_delta(
+                    &newer.sidebar_markdown_content,
+                    &self.sidebar_markdown_content,
+                ),
+                is_delta: true,
+                ..self
+            },
+            None => PageRevision {
+                is_delta: false,
+                ..self
+            },
         };
-        to_view.clone()
+
+        use crate::schema::page_revision::dsl::*;
+        diesel::update(page_revision)
+            .filter(id.eq(stored.id))
+            .set((
+                html_content.eq(stored.html_content),
+                markdown_content.eq(stored.markdown_content),
+                sidebar_html_content.eq(stored.sidebar_html_content),
+                sidebar_markdown_content.eq(stored.sidebar_markdown_content),
+                is_delta.eq(stored.is_delta),
+            ))
+            .execute(c)?;
+        Ok(())
+    }
+
+    /// Stores every published revision but the latest of each page as a delta. Returns how
+    /// many revisions were converted.
+    pub async fn compact_all(connection: &PersistDatabase) -> usize {
+        let page_ids = connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                page.select(id)
+                    .load::<Option<i32>>(c)
+                    .expect("Database error")
+            })
+            .await;
+
+        let mut compacted = 0;
+        for target_page_id in page_ids.into_iter().flatten() {
+            compacted += connection
+                .run(move |c| {
+                    chain_transaction(c, |c| {
+                        let all_revisions = Self::load_published_in(c, target_page_id)?;
+                        let stored_in_full: Vec<Option<i32>> = {
+                            use crate::schema::page_revision::dsl::*;
+                            page_revision
+                                .filter(
+                                    crate::schema::page_revision::dsl::page_id.eq(target_page_id),
+                                )
+                                .filter(is_delta.eq(false))
+                                .select(id)
+                                .load::<Option<i32>>(c)?
+                        };
+
+                        let mut compacted = 0;
+                        for index in 0..all_revisions.len().saturating_sub(1) {
+                            let revision = all_revisions[index].clone();
+                            if stored_in_full.contains(&revision.id) {
+                                revision.store_in(all_revisions.get(index + 1), c)?;
+                                compacted += 1;
+                            }
+                        }
+                        Ok(compacted)
+                    })
+                    .expect("Failed to compact page revisions")
+                })
+                .await;
+        }
+        compacted
     }
 
-    pub async fn delete_nth_revision(connection: &PersistDatabase, target_page_id: i32, revision: Option<usize>) {
-	let nth = Self::get_nth_revision(connection, target_page_id, revision).await;
-	nth.delete(connection).await;
+    /// Resolves the old positional `?revision=N` index, counted from the oldest revision,
+    /// to the id of the revision currently at that position. Drafts postdate those links, so
+    /// they are not counted.
+    pub async fn nth_revision_id(
+        connection: &PersistDatabase,
+        target_page_id: i32,
+        revision: usize,
+    ) -> Option<i32> {
+        let all_revision_ids = connection
+            .run(move |c| {
+                use crate::schema::page_revision::dsl::*;
+                page_revision
+                    .filter(crate::schema::page_revision::dsl::page_id.eq(target_page_id))
+                    .filter(is_draft.eq(false))
+                    .order((unix_time, id))
+                    .select(id)
+                    .load::<Option<i32>>(c)
+                    .expect("Database error finding page revision")
+            })
+            .await;
+        all_revision_ids.get(revision).copied().flatten()
     }
 
     pub async fn is_latest(
         connection: &PersistDatabase,
-        revision: Option<usize>,
+        revision_id: Option<i32>,
         target_page_id: i32,
     ) -> bool {
-        let target_revision_count: i64 = connection
+        match revision_id {
+            Some(rev) => {
+                let latest = Self::get_revision(connection, target_page_id, None).await;
+                latest.and_then(|latest| latest.id) == Some(rev)
+            }
+            None => true,
+        }
+    }
+
+    /// Deletes several published revisions of a page at once, re-storing the rest of the
+    /// delta chain against their new neighbours.
+    pub async fn delete_many(
+        connection: &PersistDatabase,
+        target_page_id: i32,
+        revision_ids: Vec<i32>,
+    ) {
+        connection
+            .run(move |c| {
+                chain_transaction(c, |c| {
+                    let remaining: Vec<PageRevision> = Self::load_published_in(c, target_page_id)?
+                        .into_iter()
+                        .filter(|revision| !revision_ids.contains(&revision.id.unwrap()))
+                        .collect();
+
+                    {
+                        use crate::schema::page_revision::dsl::*;
+                        diesel::delete(page_revision)
+                            .filter(id.eq_any(&revision_ids))
+                            .execute(c)?;
+                    }
+
+                    for index in 0..remaining.len() {
+                        remaining[index]
+                            .clone()
+                            .store_in(remaining.get(index + 1), c)?;
+                    }
+                    Ok(())
+                })
+                .expect("Failed to delete page_revision.")
+            })
+            .await;
+    }
+
+    /// Publishes a draft in place as the newest revision, so that links to it keep working,
+    /// and re-stores the previously latest revision as a delta against it. A draft written
+    /// against an older revision is merged with what was published since; if that conflicts
+    /// it is left unpublished and unscheduled, flagged, and the merge returned instead.
+    pub async fn publish(
+        connection: &PersistDatabase,
+        memory_connection: &MemoryDatabase,
+        parser_options: Options,
+        target_page_id: i32,
+        revision_id: i32,
+    ) -> Result<(), EditConflict> {
+        let published = connection
+            .run(move |c| {
+                chain_transaction(c, |c| {
+                    // drafts are stored in full
+                    let draft = {
+                        use crate::schema::page_revision::dsl::*;
+                        page_revision
+                            .filter(crate::schema::page_revision::dsl::page_id.eq(target_page_id))
+                            .filter(id.eq(revision_id))
+                            .filter(is_draft.eq(true))
+                            .first::<PageRevision>(c)
+                            .optional()?
+                    };
+                    let mut draft = match draft {
+                        Some(draft) => draft,
+                        None => return Ok(Ok(None)),
+                    };
+                    let previous = Self::latest_published_in(c, target_page_id)?;
+
+                    let stale_base = match (&previous, draft.base_revision_id) {
+                        (Some(previous), Some(base_revision_id))
+                            if previous.id != Some(base_revision_id) =>
+                        {
+                            Some((previous, base_revision_id))
+                        }
+                        _ => None,
+                    };
+                    if let Some((latest, base_revision_id)) = stale_base {
+                        // with the base gone, everything both sides changed is a conflict
+                        let base = Self::load_published_in(c, target_page_id)?
+                            .into_iter()
+                            .find(|revision| revision.id == Some(base_revision_id))
+                            .unwrap_or(PageRevision {
+                                markdown_content: String::new(),
+                                sidebar_markdown_content: String::new(),
+                                ..latest.clone()
+                            });
+                        let (markdown_content, markdown_conflicts) = merge3(
+                            &base.markdown_content,
+                            &draft.markdown_content,
+                            &latest.markdown_content,
+                        );
+                        let (sidebar_markdown_content, sidebar_conflicts) = merge3(
+                            &base.sidebar_markdown_content,
+                            &draft.sidebar_markdown_content,
+                            &latest.sidebar_markdown_content,
+                        );
+
+                        if markdown_conflicts || sidebar_conflicts {
+                            {
+                                use crate::schema::page_revision::dsl::*;
+                                diesel::update(page_revision)
+                                    .filter(id.eq(revision_id))
+                                    .set((
+                                        publish_at.eq(None::<i64>),
+                                        publish_conflict.eq(true),
+                                    ))
+                                    .execute(c)?;
+                            }
+                            let draft_page = page::table
+                                .filter(page::id.eq(target_page_id))
+                                .first::<Page>(c)?;
+                            return Ok(Err(EditConflict {
+                                page_info: PageInfo {
+                                    title: draft_page.title,
+                                    slug: draft_page.slug,
+                                    markdown_content: markdown_content,
+                                    sidebar_markdown_content: sidebar_markdown_content,
+                                    summary: draft.summary,
+                                    is_minor: draft.is_minor,
+                                    is_draft: false,
+                                    publish_at: None,
+                                    base_revision: latest.id,
+                                    show_child_index: draft_page.show_child_index,
+                                },
+                                latest_revision_id: latest.id,
+                                has_conflicts: true,
+                            }));
+                        }
+
+                        draft = PageRevision {
+                            html_content: md2html(markdown_content.clone(), parser_options),
+                            markdown_content: markdown_content,
+                            sidebar_html_content: md2html(
+                                sidebar_markdown_content.clone(),
+                                parser_options,
+                            ),
+                            sidebar_markdown_content: sidebar_markdown_content,
+                            ..draft
+                        };
+                    }
+
+                    let (published_iso_time, published_unix_time) = revision_timestamp();
+
+                    let published = PageRevision {
+                        iso_time: published_iso_time.clone(),
+                        unix_time: published_unix_time,
+                        is_delta: false,
+                        is_draft: false,
+                        publish_at: None,
+                        publish_conflict: false,
+                        ..draft
+                    };
+
+                    {
+                        use crate::schema::page_revision::dsl::*;
+                        diesel::update(page_revision)
+                            .filter(id.eq(revision_id))
+                            .set((
+                                iso_time.eq(published_iso_time),
+                                unix_time.eq(published_unix_time),
+                                html_content.eq(&published.html_content),
+                                markdown_content.eq(&published.markdown_content),
+                                sidebar_html_content.eq(&published.sidebar_html_content),
+                                sidebar_markdown_content.eq(&published.sidebar_markdown_content),
+                                is_draft.eq(false),
+                                publish_at.eq(None::<i64>),
+                                publish_conflict.eq(false),
+                            ))
+                            .execute(c)?;
+                    }
+
+                    if let Some(previous) = previous {
+                        previous.store_in(Some(&published), c)?;
+                    }
+                    Ok(Ok(Some(published)))
+                })
+                .expect("Failed to publish page revision")
+            })
+            .await?;
+
+        let published = match published {
+            Some(published) => published,
+            None => return Ok(()),
+        };
+
+        SearchResult::update_content(
+            memory_connection,
+            target_page_id,
+            published.markdown_content,
+            published.sidebar_markdown_content,
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Publishes every draft whose scheduled time has passed. Returns how many were published;
+    /// drafts that conflict with what was published since are flagged and left as drafts.
+    pub async fn publish_due(
+        connection: &PersistDatabase,
+        memory_connection: &MemoryDatabase,
+        parser_options: Options,
+    ) -> usize {
+        let now = Utc::now().timestamp_millis();
+        let due = connection
             .run(move |c| {
                 use crate::schema::page_revision::dsl::*;
                 page_revision
-                    .filter(crate::schema::page_revision::dsl::page_id.eq(target_page_id))
-                    .order(unix_time)
-                    .count()
-                    .get_result(c)
-                    .expect("Error finding count of revisions")
+                    .filter(is_draft.eq(true))
+                    .filter(publish_at.le(now))
+                    .order((unix_time, id))
+                    .select((page_id, id))
+                    .load::<(Option<i32>, Option<i32>)>(c)
+                    .expect("Database error finding page revision")
             })
             .await;
-        (revision.is_some() && revision.unwrap() == (target_revision_count - 1) as usize)
-            || revision.is_none()
+
+        let mut published = 0;
+        for (target_page_id, revision_id) in due.iter() {
+            let publish = Self::publish(
+                connection,
+                memory_connection,
+                parser_options,
+                target_page_id.unwrap(),
+                revision_id.unwrap(),
+            )
+            .await;
+            if publish.is_ok() {
+                published += 1;
+            }
+        }
+        published
     }
 
     pub async fn delete(self, connection: &PersistDatabase) {
-	connection
-	    .run(move |c| {
-                use crate::schema::page_revision::dsl::*;
-                diesel::delete(page_revision)
-                    .filter(crate::schema::page_revision::id.eq(self.id))
-                    .execute(c)
-                    .expect("Failed to delete page_revision.")
-	    })
-	    .await;
+        connection
+            .run(move |c| {
+                chain_transaction(c, |c| {
+                    // the next older revision is stored against this one, so re-store it
+                    // against whichever revision will follow it instead
+                    if !self.is_draft {
+                        let all_revisions = Self::load_published_in(c, self.page_id.unwrap())?;
+                        let index = all_revisions
+                            .iter()
+                            .position(|revision| revision.id == self.id)
+                            .expect("No such page revision found.");
+                        if index > 0 {
+                            all_revisions[index - 1]
+                                .clone()
+                                .store_in(all_revisions.get(index + 1), c)?;
+                        }
+                    }
+
+                    diesel::delete(crate::schema::page_revision::dsl::page_revision)
+                        .filter(crate::schema::page_revision::id.eq(self.id))
+                        .execute(c)?;
+                    Ok(())
+                })
+                .expect("Failed to delete page_revision.")
+            })
+            .await;
 	// TODO update the search table
     }
 }
@@ -146,6 +737,279 @@ pub struct Page {
     pub title: String,
     #[diesel(sql_type = Text)]
     pub slug: String,
+    /// When the page was moved to the trash, in unix milliseconds.
+    #[diesel(sql_type = Nullable<BigInt>)]
+    pub deleted_at: Option<i64>,
+    /// The page whose deletion moved this one to the trash, along with its subtree.
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub trash_root_id: Option<i32>,
+    /// Place among its siblings, when their parent sorts them by hand.
+    #[diesel(sql_type = Integer)]
+    pub position: i32,
+    /// How the children of this page are ordered; see `ChildSort`.
+    #[diesel(sql_type = Text)]
+    pub child_sort: String,
+    /// Slugs from the root down, as `/a/b` (empty for the root), kept up to date on
+    /// renames and moves.
+    #[diesel(sql_type = Text)]
+    pub path: String,
+    /// Whether the index of child pages is shown below the content, even without the
+    /// placeholder for it.
+    #[diesel(sql_type = Bool)]
+    pub show_child_index: bool,
+}
+
+/// How the children of a page are ordered in the nav and in child listings.
+#[derive(FromFormField, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ChildSort {
+    Manual,
+    Alphabetical,
+    Newest,
+}
+
+impl ChildSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChildSort::Manual => "manual",
+            ChildSort::Alphabetical => "alphabetical",
+            ChildSort::Newest => "newest",
+        }
+    }
+}
+
+/// A child page as listed in the child index of its parent.
+#[derive(Serialize, Debug, Clone)]
+pub struct ChildSummary {
+    pub title: String,
+    pub path: String,
+    /// Time of the latest published revision, in unix milliseconds.
+    pub unix_time: i64,
+    pub first_paragraph: String,
+}
+
+/// A deleted subtree as listed in the trash.
+#[derive(Serialize, Debug, Clone)]
+pub struct TrashedPage {
+    pub page: Page,
+    pub path: String,
+    pub page_count: usize,
+}
+
+/// How many revisions to keep of the pages in a subtree, or of every page not covered by a
+/// subtree policy when `page_id` is None: all revisions younger than `keep_all_days`, then
+/// the newest of each week up to `keep_weekly_days`, then the newest of each month up to
+/// `keep_monthly_days` (or forever). The latest published revision is always kept.
+#[derive(Queryable, Insertable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = retention_policy)]
+pub struct RetentionPolicy {
+    pub id: Option<i32>,
+    pub page_id: Option<i32>,
+    pub keep_all_days: i32,
+    pub keep_weekly_days: i32,
+    pub keep_monthly_days: Option<i32>,
+}
+
+/// A retention policy as listed for admins, with the path of the subtree it covers.
+#[derive(Serialize, Debug, Clone)]
+pub struct ListedRetentionPolicy {
+    pub policy: RetentionPolicy,
+    pub path: Option<String>,
+}
+
+/// A revision that pruning would remove.
+#[derive(Serialize, Debug, Clone)]
+pub struct PrunableRevision {
+    pub page_id: i32,
+    pub path: String,
+    pub title: String,
+    pub revision: PageRevision,
+    pub author: Option<String>,
+}
+
+impl RetentionPolicy {
+    pub async fn list(connection: &PersistDatabase) -> Vec<ListedRetentionPolicy> {
+        let policies = connection
+            .run(move |c| {
+                use crate::schema::retention_policy::dsl::*;
+                retention_policy
+                    .order(id)
+                    .load::<RetentionPolicy>(c)
+                    .expect("Database error finding retention policies")
+            })
+            .await;
+        let paths = Page::all_paths(connection).await;
+
+        policies
+            .into_iter()
+            .map(|policy| ListedRetentionPolicy {
+                path: policy.page_id.and_then(|id| paths.get(&id).cloned()),
+                policy: policy,
+            })
+            .collect()
+    }
+
+    /// Saves a policy, replacing the one already set for the same subtree (or globally).
+    pub async fn save(self, connection: &PersistDatabase) {
+        connection
+            .run(move |c| {
+                use crate::schema::retention_policy::dsl::*;
+                match self.page_id {
+                    Some(target_page_id) => diesel::delete(retention_policy)
+                        .filter(page_id.eq(target_page_id))
+                        .execute(c),
+                    None => diesel::delete(retention_policy)
+                        .filter(page_id.is_null())
+                        .execute(c),
+                }
+                .expect("Failed to delete retention policy.");
+                diesel::insert_into(retention_policy)
+                    .values(self)
+                    .execute(c)
+                    .expect("Error saving retention policy");
+            })
+            .await;
+    }
+
+    pub async fn delete(connection: &PersistDatabase, policy_id: i32) {
+        connection
+            .run(move |c| {
+                use crate::schema::retention_policy::dsl::*;
+                diesel::delete(retention_policy)
+                    .filter(id.eq(policy_id))
+                    .execute(c)
+                    .expect("Failed to delete retention policy.")
+            })
+            .await;
+    }
+
+    /// Picks which of a page's published revisions, given newest first by time, the policy
+    /// would drop.
+    fn prunable(&self, revision_times: &[i64], now: i64) -> Vec<usize> {
+        const DAY: i64 = 24 * 60 * 60 * 1000;
+
+        let mut kept_periods = HashSet::new();
+        let mut prunable = Vec::new();
+        for (index, time) in revision_times.iter().enumerate() {
+            let age = now - time;
+            let period = if index == 0 || age < self.keep_all_days as i64 * DAY {
+                continue;
+            } else if age < self.keep_weekly_days as i64 * DAY {
+                format!("week {}", time / (7 * DAY))
+            } else if self
+                .keep_monthly_days
+                .map_or(true, |days| age < days as i64 * DAY)
+            {
+                match Utc.timestamp_millis_opt(*time).single() {
+                    Some(datetime) => datetime.format("month %Y-%m").to_string(),
+                    None => continue,
+                }
+            } else {
+                prunable.push(index);
+                continue;
+            };
+            // newest first, so the revision kept for each period is its newest
+            if !kept_periods.insert(period) {
+                prunable.push(index);
+            }
+        }
+        prunable
+    }
+
+    /// Lists every revision the retention policies would remove, page by page. Each page
+    /// follows the policy of the nearest subtree it is in, or the global policy.
+    pub async fn plan_prune(connection: &PersistDatabase) -> Vec<PrunableRevision> {
+        let policies: HashMap<Option<i32>, RetentionPolicy> = connection
+            .run(move |c| {
+                use crate::schema::retention_policy::dsl::*;
+                retention_policy
+                    .load::<RetentionPolicy>(c)
+                    .expect("Database error finding retention policies")
+            })
+            .await
+            .into_iter()
+            .map(|policy| (policy.page_id, policy))
+            .collect();
+        if policies.is_empty() {
+            return Vec::new();
+        }
+
+        let pages = connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                page.order(id).load::<Page>(c).expect("Database error")
+            })
+            .await;
+        let parents: HashMap<Option<i32>, Option<i32>> =
+            pages.iter().map(|page| (page.id, page.parent_id)).collect();
+        let paths = Page::all_paths(connection).await;
+
+        let now = Utc::now().timestamp_millis();
+        let mut plan = Vec::new();
+        for page in pages {
+            let mut ancestor = page.id;
+            let policy = loop {
+                if let Some(policy) = policies.get(&ancestor) {
+                    break Some(policy);
+                }
+                match ancestor {
+                    Some(ancestor_id) => ancestor = parents[&Some(ancestor_id)],
+                    None => break None,
+                }
+            };
+            let policy = match policy {
+                Some(policy) => policy,
+                None => continue,
+            };
+
+            let mut listings =
+                PageRevision::list_for_page(connection, page.id.unwrap(), false).await;
+            listings.reverse();
+            let times: Vec<i64> = listings
+                .iter()
+                .map(|listing| listing.revision.unix_time)
+                .collect();
+
+            for index in policy.prunable(&times, now) {
+                let listing = listings[index].clone();
+                plan.push(PrunableRevision {
+                    page_id: page.id.unwrap(),
+                    path: paths.get(&page.id.unwrap()).cloned().unwrap_or_default(),
+                    title: page.title.clone(),
+                    revision: listing.revision,
+                    author: listing.author,
+                });
+            }
+        }
+        plan
+    }
+
+    /// Deletes the given revisions, as far as the retention policies still allow it. Returns
+    /// how many were deleted.
+    pub async fn prune(connection: &PersistDatabase, revision_ids: Vec<i32>) -> usize {
+        let mut by_page: HashMap<i32, Vec<i32>> = HashMap::new();
+        for prunable in Self::plan_prune(connection).await {
+            let revision_id = prunable.revision.id.unwrap();
+            if revision_ids.contains(&revision_id) {
+                by_page.entry(prunable.page_id).or_default().push(revision_id);
+            }
+        }
+
+        let mut pruned = 0;
+        for (target_page_id, page_revision_ids) in by_page {
+            pruned += page_revision_ids.len();
+            PageRevision::delete_many(connection, target_page_id, page_revision_ids).await;
+        }
+        pruned
+    }
+}
+
+/// An edit made against a revision that has since been superseded, with its content merged
+/// with the changes saved in the meantime.
+#[derive(Serialize)]
+pub struct EditConflict {
+    pub page_info: PageInfo,
+    pub latest_revision_id: Option<i32>,
+    pub has_conflicts: bool,
 }
 
 impl Page {
@@ -159,39 +1023,56 @@ impl Page {
             slug: "".to_string(),
             markdown_content: "Default root".to_string(),
             sidebar_markdown_content: "".to_string(),
+            summary: "".to_string(),
+            is_minor: false,
+            is_draft: false,
+            publish_at: None,
+            base_revision: None,
+            show_child_index: false,
         };
 
-        let empty = PathBuf::new();
-
         Self::create_child_and_insert(
             None,
-            empty,
             default_root_info,
+            None,
             state,
             connection,
             memory_connection,
         )
         .await;
-        // TODO figure out if passing a blank path to create_and_insert will work okay
     }
 
     pub async fn create_child_and_insert(
         parent_id: Option<i32>,
-        parent_path: PathBuf,
         page_info: PageInfo,
+        admin_id: Option<i32>,
         state: &State<ManagedState>,
         connection: &PersistDatabase,
         memory_connection: &MemoryDatabase,
     ) -> () {
+        let (position, parent_page_path) = match parent_id {
+            Some(parent_id) => (
+                Self::next_position(connection, parent_id).await,
+                Some(Self::path_of(connection, parent_id).await),
+            ),
+            None => (0, None),
+        };
+        let new_page_path = Self::child_path(parent_page_path.as_deref(), &page_info.slug);
+
         let page = Page {
             id: None,
             parent_id: parent_id,
             title: page_info.title.clone(),
             slug: page_info.slug.clone(),
+            deleted_at: None,
+            trash_root_id: None,
+            position: position,
+            child_sort: ChildSort::Manual.as_str().to_string(),
+            path: new_page_path.clone(),
+            show_child_index: page_info.show_child_index,
         };
 
-        let mut page_path = parent_path.clone();
-        page_path.push(page_info.slug.clone());
+        let nav_page = page.clone();
 
         connection
             .run(move |c| {
@@ -211,11 +1092,18 @@ impl Page {
             })
             .await;
 
+        state.nav.upsert(&Page {
+            id: page_id,
+            ..nav_page
+        });
+
+        let (iso_time, unix_time) = revision_timestamp();
+
         let page_revision = PageRevision {
             id: None,
             page_id: page_id,
-            iso_time: Utc::now().format("%Y-%m-%d").to_string(),
-            unix_time: Utc::now().timestamp() as i32,
+            iso_time: iso_time,
+            unix_time: unix_time,
             html_content: md2html(page_info.markdown_content.clone(), state.parser_options),
             markdown_content: page_info.markdown_content.clone(),
             sidebar_html_content: md2html(
@@ -223,16 +1111,17 @@ impl Page {
                 state.parser_options,
             ),
             sidebar_markdown_content: page_info.sidebar_markdown_content.clone(),
+            admin_id: admin_id,
+            summary: page_info.summary.clone(),
+            is_minor: page_info.is_minor,
+            is_delta: false,
+            is_draft: false,
+            publish_at: None,
+            base_revision_id: None,
+            publish_conflict: false,
         };
 
-        connection
-            .run(move |c| {
-                diesel::insert_into(crate::schema::page_revision::dsl::page_revision)
-                    .values(page_revision)
-                    .execute(c)
-                    .expect("Error saving page revision");
-            })
-            .await;
+        page_revision.insert(connection).await;
 
         memory_connection
 	    .run(move |c| {
@@ -242,7 +1131,7 @@ impl Page {
                     "#
             );
             let binding = query.bind::<Nullable<Integer>, _>(page_id)
-                    .bind::<Text, _>(page_path.display().to_string())
+                    .bind::<Text, _>(new_page_path)
                     .bind::<Text, _>(page_info.title.clone())
                     .bind::<Text, _>(page_info.markdown_content.clone())
                     .bind::<Text, _>(page_info.sidebar_markdown_content.clone());
@@ -250,27 +1139,95 @@ impl Page {
 	    }).await;
     }
 
+    /// Saves an edit as the newest revision, or as a draft that leaves the page and the
+    /// search table as they are until it is published; drafts carry no title, slug or child
+    /// index changes, which `edit_page` turns away. An edit based on a
+    /// revision that is no longer the latest is not saved, but merged into an `EditConflict`.
     pub async fn edit_and_update(
-        edit_path: PathBuf,
+        to_edit: Page,
         edit_page_info: PageInfo,
+        admin_id: Option<i32>,
         connection: &PersistDatabase,
         memory_connection: &MemoryDatabase,
         state: &State<ManagedState>,
-    ) {
-        let to_edit = Self::from_path(&edit_path, connection).await;
+    ) -> Result<(), EditConflict> {
+        if let Some(base_revision_id) = edit_page_info.base_revision {
+            let page_id = to_edit.id.unwrap();
+            let latest = PageRevision::get_revision(connection, page_id, None)
+                .await
+                .expect("No published page revision found.");
+            if latest.id != Some(base_revision_id) {
+                // with the base gone, everything both sides changed is left as a conflict
+                let base = PageRevision::get_revision(connection, page_id, Some(base_revision_id))
+                    .await
+                    .unwrap_or(PageRevision {
+                        markdown_content: String::new(),
+                        sidebar_markdown_content: String::new(),
+                        ..latest.clone()
+                    });
+                let (markdown_content, markdown_conflicts) = merge3(
+                    &base.markdown_content,
+                    &edit_page_info.markdown_content,
+                    &latest.markdown_content,
+                );
+                let (sidebar_markdown_content, sidebar_conflicts) = merge3(
+                    &base.sidebar_markdown_content,
+                    &edit_page_info.sidebar_markdown_content,
+                    &latest.sidebar_markdown_content,
+                );
+                return Err(EditConflict {
+                    page_info: PageInfo {
+                        markdown_content: markdown_content,
+                        sidebar_markdown_content: sidebar_markdown_content,
+                        base_revision: latest.id,
+                        ..edit_page_info
+                    },
+                    latest_revision_id: latest.id,
+                    has_conflicts: markdown_conflicts || sidebar_conflicts,
+                });
+            }
+        }
+
+        // scheduling a time implies a draft
+        let publish_at = edit_page_info
+            .publish_at
+            .as_deref()
+            .and_then(|value| parse_local_datetime(value, state.display_timezone));
+        let is_draft = edit_page_info.is_draft || publish_at.is_some();
 
         let edited = Page {
             id: to_edit.id,
             parent_id: to_edit.parent_id,
             title: edit_page_info.title.clone(),
             slug: edit_page_info.slug.clone(),
+            deleted_at: None,
+            trash_root_id: None,
+            position: to_edit.position,
+            child_sort: to_edit.child_sort.clone(),
+            path: Self::child_path(
+                to_edit.path.rsplit_once('/').map(|(parent_path, _)| parent_path),
+                &edit_page_info.slug,
+            ),
+            show_child_index: edit_page_info.show_child_index,
         };
 
+        // publishing a draft merges in whatever was published after the revision it was
+        // written against
+        let base_revision_id = match (is_draft, edit_page_info.base_revision) {
+            (false, _) => None,
+            (true, Some(base_revision_id)) => Some(base_revision_id),
+            (true, None) => PageRevision::get_revision(connection, to_edit.id.unwrap(), None)
+                .await
+                .and_then(|latest| latest.id),
+        };
+
+        let (iso_time, unix_time) = revision_timestamp();
+
         let new_revision = PageRevision {
             id: None,
             page_id: to_edit.id,
-            iso_time: Utc::now().format("%Y-%m-%d").to_string(),
-            unix_time: Utc::now().timestamp() as i32,
+            iso_time: iso_time,
+            unix_time: unix_time,
             html_content: md2html(
                 edit_page_info.markdown_content.clone(),
                 state.parser_options,
@@ -281,256 +1238,1325 @@ impl Page {
                 state.parser_options,
             ),
             sidebar_markdown_content: edit_page_info.sidebar_markdown_content.clone(),
+            admin_id: admin_id,
+            summary: edit_page_info.summary.clone(),
+            is_minor: edit_page_info.is_minor,
+            is_delta: false,
+            is_draft: is_draft,
+            publish_at: publish_at,
+            base_revision_id: base_revision_id,
+            publish_conflict: false,
+        };
+
+        if is_draft {
+            new_revision.insert(connection).await;
+            return Ok(());
+        }
+
+        // renaming the slug changes the path of the whole subtree
+        let old_paths = match edited.slug != to_edit.slug {
+            true => Some(Self::subtree_paths(connection, to_edit.id.unwrap()).await),
+            false => None,
+        };
+
+        state.nav.upsert(&edited);
+        let edited_path = edited.path.clone();
+
+        connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                diesel::update(page)
+                    .filter(id.eq(to_edit.id))
+                    .set(&edited)
+                    .execute(c)
+                    .expect("Failed to update page from path")
+            })
+            .await;
+
+        new_revision.insert(connection).await;
+
+        memory_connection
+        .run(move |c| {
+	    let query = sql_query("UPDATE search SET path=?, title=?, markdown_content=?, sidebar_markdown_content=? WHERE id = ?");
+	    query
+        .bind::<Text, _>(edited_path)
+        .bind::<Text, _>(edit_page_info.title.clone())
+        .bind::<Text, _>(edit_page_info.markdown_content.clone())
+        .bind::<Text, _>(edit_page_info.sidebar_markdown_content.clone())
+        .bind::<Nullable<Integer>, _>(to_edit.id)
+        .execute(c).expect("Database error");
+	}).await;
+
+        if let Some(old_paths) = old_paths {
+            Self::update_subtree_paths(connection, to_edit.id.unwrap()).await;
+            Self::paths_changed(connection, memory_connection, old_paths).await;
+        }
+
+        Ok(())
+    }
+
+    /// Restores an older revision by saving a copy of it as the newest revision, so that the
+    /// revisions in between stay in the history. Returns None if there is no such published
+    /// revision; drafts are published, not restored.
+    pub async fn restore_revision(
+        self,
+        revision_id: i32,
+        admin_id: Option<i32>,
+        connection: &PersistDatabase,
+        memory_connection: &MemoryDatabase,
+    ) -> Option<()> {
+        let old_revision =
+            PageRevision::get_revision(connection, self.id.unwrap(), Some(revision_id))
+                .await
+                .filter(|revision| !revision.is_draft)?;
+
+        let (iso_time, unix_time) = revision_timestamp();
+
+        let restored = PageRevision {
+            id: None,
+            page_id: self.id,
+            iso_time: iso_time,
+            unix_time: unix_time,
+            admin_id: admin_id,
+            summary: format!("Restored revision from {}", old_revision.iso_time),
+            is_minor: false,
+            is_delta: false,
+            is_draft: false,
+            publish_at: None,
+            base_revision_id: None,
+            publish_conflict: false,
+            ..old_revision
+        };
+
+        let markdown_content = restored.markdown_content.clone();
+        let sidebar_markdown_content = restored.sidebar_markdown_content.clone();
+
+        restored.insert(connection).await;
+
+        SearchResult::update_content(
+            memory_connection,
+            self.id.unwrap(),
+            markdown_content,
+            sidebar_markdown_content,
+        )
+        .await;
+
+        Some(())
+    }
+
+    /// Moves the page to the trash, along with every descendant not already there.
+    pub async fn delete(
+        self,
+        connection: &PersistDatabase,
+        memory_connection: &MemoryDatabase,
+        state: &State<ManagedState>,
+    ) {
+        let subtree_ids = Self::subtree_ids(connection, self.id.unwrap(), false).await;
+        let deleted_ids = subtree_ids.clone();
+        let now = Utc::now().timestamp_millis();
+
+        connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                diesel::update(page)
+                    .filter(crate::schema::page::id.eq_any(deleted_ids))
+                    .set((deleted_at.eq(now), trash_root_id.eq(self.id)))
+                    .execute(c)
+                    .expect("Failed to delete page.")
+            })
+            .await;
+
+        state.nav.remove_subtree(self.id.unwrap());
+
+        for subtree_id in subtree_ids {
+            memory_connection
+                .run(move |c| {
+                    let query = sql_query("DELETE FROM search WHERE id = ?");
+                    query
+                        .bind::<Integer, _>(subtree_id)
+                        .execute(c)
+                        .expect("Database error");
+                })
+                .await;
+        }
+    }
+
+    /// Moves the page, with its subtree, under a new parent.
+    pub async fn move_to(
+        self,
+        new_parent: Page,
+        connection: &PersistDatabase,
+        memory_connection: &MemoryDatabase,
+        state: &State<ManagedState>,
+    ) -> Result<(), &'static str> {
+        if self.parent_id.is_none() {
+            return Err("The root page cannot be moved.");
+        }
+
+        let old_paths = Self::subtree_paths(connection, self.id.unwrap()).await;
+        if old_paths.contains_key(&new_parent.id.unwrap()) {
+            return Err("A page cannot be moved into its own subtree.");
+        }
+
+        let moved_id = self.id;
+        if Self::slug_taken(connection, new_parent.id.unwrap(), self.slug.clone(), moved_id).await {
+            return Err("Another page under the new parent already has this slug.");
+        }
+
+        let new_parent_id = new_parent.id;
+        let new_position = Self::next_position(connection, new_parent_id.unwrap()).await;
+
+        connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                diesel::update(page)
+                    .filter(id.eq(moved_id))
+                    .set((parent_id.eq(new_parent_id), position.eq(new_position)))
+                    .execute(c)
+                    .expect("Failed to move page.")
+            })
+            .await;
+        Self::update_subtree_paths(connection, moved_id.unwrap()).await;
+
+        state.nav.upsert(&Page {
+            parent_id: new_parent_id,
+            position: new_position,
+            path: Self::child_path(Some(&new_parent.path), &self.slug),
+            ..self
+        });
+
+        Self::paths_changed(connection, memory_connection, old_paths).await;
+
+        Ok(())
+    }
+
+    /// Normalizes the slug of a page being created under or edited within `parent_id`,
+    /// generating it from the title when left blank, and checks that no sibling has it. The
+    /// root page keeps its empty slug.
+    pub async fn validate_slug(
+        connection: &PersistDatabase,
+        parent_id: Option<i32>,
+        own_id: Option<i32>,
+        page_info: &PageInfo,
+    ) -> Result<String, &'static str> {
+        let parent_id = match parent_id {
+            Some(parent_id) => parent_id,
+            None => return Ok(String::new()),
+        };
+
+        let slug = normalize_slug(&page_info.slug, &page_info.title);
+        if slug == "" {
+            return Err("The slug is empty; give the page a slug or a title to make one from.");
+        }
+        if Self::slug_taken(connection, parent_id, slug.clone(), own_id).await {
+            return Err("Another page under the same parent already has this slug.");
+        }
+        Ok(slug)
+    }
+
+    /// Whether a page under `parent_id`, other than `own_id`, already has `slug`.
+    async fn slug_taken(
+        connection: &PersistDatabase,
+        target_parent_id: i32,
+        target_slug: String,
+        own_id: Option<i32>,
+    ) -> bool {
+        let taken = connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                let query = page
+                    .filter(parent_id.eq(target_parent_id))
+                    .filter(slug.eq(target_slug))
+                    .filter(deleted_at.is_null())
+                    .into_boxed();
+                let query = match own_id {
+                    Some(own_id) => query.filter(id.ne(own_id)),
+                    None => query,
+                };
+                query
+                    .count()
+                    .get_result::<i64>(c)
+                    .expect("Database error")
+            })
+            .await;
+        taken > 0
+    }
+
+    /// Position that puts a new child of `target_parent_id` after its existing children.
+    async fn next_position(connection: &PersistDatabase, target_parent_id: i32) -> i32 {
+        let last = connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                page.filter(parent_id.eq(target_parent_id))
+                    .select(diesel::dsl::max(position))
+                    .first::<Option<i32>>(c)
+                    .expect("Database error")
+            })
+            .await;
+        last.map_or(0, |last| last + 1)
+    }
+
+    /// Orders siblings the way their parent's `child_sort` asks for.
+    pub fn sort_siblings(siblings: &mut Vec<Page>, child_sort: &str) {
+        match child_sort {
+            "alphabetical" => siblings.sort_by_key(|sibling| sibling.title.to_lowercase()),
+            "newest" => siblings.sort_by(|a, b| b.id.cmp(&a.id)),
+            _ => siblings.sort_by_key(|sibling| (sibling.position, sibling.id)),
+        }
+    }
+
+    /// Groups pages by parent id, each group in its parent's order.
+    pub fn sorted_children(pages: Vec<Page>) -> HashMap<Option<i32>, Vec<Page>> {
+        let child_sorts: HashMap<Option<i32>, String> = pages
+            .iter()
+            .map(|parent| (parent.id, parent.child_sort.clone()))
+            .collect();
+
+        let mut children: HashMap<Option<i32>, Vec<Page>> = HashMap::new();
+        for child in pages {
+            children.entry(child.parent_id).or_default().push(child);
+        }
+        for (parent, siblings) in children.iter_mut() {
+            let child_sort = child_sorts
+                .get(parent)
+                .map_or(ChildSort::Manual.as_str(), |child_sort| child_sort.as_str());
+            Self::sort_siblings(siblings, child_sort);
+        }
+        children
+    }
+
+    /// The children of a page outside the trash, in the page's order.
+    pub async fn children(&self, connection: &PersistDatabase) -> Vec<Page> {
+        let target_parent_id = self.id;
+        let mut children = connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                page.filter(parent_id.eq(target_parent_id))
+                    .filter(deleted_at.is_null())
+                    .load::<Page>(c)
+                    .expect("Database error")
+            })
+            .await;
+        Self::sort_siblings(&mut children, &self.child_sort);
+        children
+    }
+
+    /// The children of a page with their latest published revision's date and opening
+    /// paragraph, for the child index.
+    pub async fn child_index(&self, connection: &PersistDatabase) -> Vec<ChildSummary> {
+        let mut index = Vec::new();
+        for child in self.children(connection).await {
+            let latest = match PageRevision::get_revision(connection, child.id.unwrap(), None).await
+            {
+                Some(latest) => latest,
+                None => continue,
+            };
+            index.push(ChildSummary {
+                title: child.title,
+                path: child.path,
+                unix_time: latest.unix_time,
+                first_paragraph: first_paragraph(&latest.markdown_content),
+            });
+        }
+        index
+    }
+
+    /// Sets how the children of a page are ordered, along with their manual positions.
+    pub async fn reorder_children(
+        &self,
+        connection: &PersistDatabase,
+        state: &State<ManagedState>,
+        new_child_sort: ChildSort,
+        positions: HashMap<i32, i32>,
+    ) {
+        state.nav.reorder(self.id.unwrap(), new_child_sort, positions.clone());
+
+        let target_id = self.id;
+        connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                diesel::update(page)
+                    .filter(id.eq(target_id))
+                    .set(child_sort.eq(new_child_sort.as_str()))
+                    .execute(c)
+                    .expect("Failed to update page.");
+                for (child_id, child_position) in positions {
+                    diesel::update(page)
+                        .filter(id.eq(child_id))
+                        .filter(parent_id.eq(target_id))
+                        .set(position.eq(child_position))
+                        .execute(c)
+                        .expect("Failed to update page.");
+                }
+            })
+            .await;
+    }
+
+    /// Path of a page with `slug` under a parent at `parent_path`, or at the top without one.
+    fn child_path(parent_path: Option<&str>, slug: &str) -> String {
+        match parent_path {
+            Some(parent_path) => format!("{}/{}", parent_path, slug),
+            None => slug.to_string(),
+        }
+    }
+
+    async fn path_of(connection: &PersistDatabase, page_id: i32) -> String {
+        connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                page.filter(id.eq(page_id))
+                    .select(path)
+                    .first::<String>(c)
+                    .expect("No such page found")
+            })
+            .await
+    }
+
+    /// Rewrites the stored paths of a page and all its descendants, trashed or not, from its
+    /// parent's path and their slugs. Called after a page is renamed or moved.
+    async fn update_subtree_paths(connection: &PersistDatabase, root_id: i32) {
+        let query = sql_query(
+            r#"
+             WITH RECURSIVE CTE AS (
+             SELECT p.id, CASE WHEN p.parent_id IS NULL THEN p.slug
+                               ELSE parent.path || '/' || p.slug END AS path
+             FROM page p
+             LEFT JOIN page parent ON p.parent_id = parent.id
+             WHERE p.id = ?
+             UNION ALL
+             SELECT p.id, CTE.path || '/' || p.slug
+             FROM page p
+             JOIN CTE ON p.parent_id = CTE.id
+           )
+           UPDATE page SET path = (SELECT CTE.path FROM CTE WHERE CTE.id = page.id)
+           WHERE id IN (SELECT id FROM CTE)
+"#,
+        );
+        connection
+            .run(move |c| {
+                query
+                    .bind::<Integer, _>(root_id)
+                    .execute(c)
+                    .expect("Failed to update page paths.")
+            })
+            .await;
+    }
+
+    /// Rebuilds any stored path that disagrees with the one given by `parent_id` and the
+    /// slugs above it, returning how many were wrong. Run at startup.
+    pub async fn check_paths(connection: &PersistDatabase) -> usize {
+        let query = sql_query(
+            r#"
+             WITH RECURSIVE CTE AS (
+             SELECT id, slug AS path
+             FROM page
+             WHERE parent_id IS NULL
+             UNION ALL
+             SELECT p.id, CTE.path || '/' || p.slug
+             FROM page p
+             JOIN CTE ON p.parent_id = CTE.id
+           )
+           UPDATE page SET path = (SELECT CTE.path FROM CTE WHERE CTE.id = page.id)
+           WHERE id IN (
+             SELECT CTE.id FROM CTE WHERE CTE.id = page.id AND CTE.path IS NOT page.path
+           )
+"#,
+        );
+        connection
+            .run(move |c| query.execute(c).expect("Failed to check page paths."))
+            .await
+    }
+
+    /// Paths of a page and its descendants outside the trash, by id.
+    async fn subtree_paths(connection: &PersistDatabase, root_id: i32) -> HashMap<i32, String> {
+        let paths = Self::all_paths(connection).await;
+        Self::subtree_ids(connection, root_id, false)
+            .await
+            .into_iter()
+            .map(|subtree_id| (subtree_id, paths.get(&subtree_id).cloned().unwrap_or_default()))
+            .collect()
+    }
+
+    /// Called once pages have been renamed or moved, with their paths from before. The old
+    /// paths are kept as redirects, and the paths in the search table are rewritten.
+    async fn paths_changed(
+        connection: &PersistDatabase,
+        memory_connection: &MemoryDatabase,
+        old_paths: HashMap<i32, String>,
+    ) {
+        let paths = Self::all_paths(connection).await;
+        for (changed_id, previous_path) in old_paths {
+            let path = paths.get(&changed_id).cloned().unwrap_or_default();
+            if path == previous_path {
+                continue;
+            }
+
+            connection
+                .run(move |c| {
+                    use crate::schema::page_redirect;
+                    diesel::replace_into(page_redirect::table)
+                        .values((
+                            page_redirect::old_path.eq(previous_path),
+                            page_redirect::page_id.eq(changed_id),
+                        ))
+                        .execute(c)
+                        .expect("Error saving page redirect")
+                })
+                .await;
+
+            memory_connection
+                .run(move |c| {
+                    let query = sql_query("UPDATE search SET path=? WHERE id = ?");
+                    query
+                        .bind::<Text, _>(path)
+                        .bind::<Integer, _>(changed_id)
+                        .execute(c)
+                        .expect("Database error");
+                })
+                .await;
+        }
+    }
+
+    /// Looks up a page by path like `try_from_path`. A path the page was at before being renamed
+    /// or moved gives `Err(Some(current_path))`, and an unknown path `Err(None)`.
+    pub async fn lookup(
+        path: &PathBuf,
+        connection: &PersistDatabase,
+    ) -> Result<Self, Option<PathBuf>> {
+        if let Some(found) = Self::try_from_path(path, connection).await {
+            return Ok(found);
+        }
+
+        let path = path.to_str().unwrap().to_string();
+        let path_spec = if path != "" {
+            format!("/{}", path)
+        } else {
+            path
+        };
+        let redirected = connection
+            .run(move |c| {
+                use crate::schema::page_redirect::dsl::*;
+                page_redirect
+                    .inner_join(crate::schema::page::table)
+                    .filter(old_path.eq(path_spec))
+                    .filter(crate::schema::page::deleted_at.is_null())
+                    .select(page_id)
+                    .first::<i32>(c)
+                    .optional()
+                    .expect("Database error finding page redirect")
+            })
+            .await;
+
+        match redirected {
+            Some(redirected_id) => {
+                let current = Self::path_of(connection, redirected_id).await;
+                Err(Some(PathBuf::from(current.trim_start_matches('/'))))
+            }
+            None => Err(None),
+        }
+    }
+
+    /// Ids of a page and its descendants, leaving out those in the trash unless
+    /// `include_trashed` is set.
+    async fn subtree_ids(
+        connection: &PersistDatabase,
+        root_id: i32,
+        include_trashed: bool,
+    ) -> Vec<i32> {
+        let query = sql_query(
+            r#"
+             WITH RECURSIVE CTE AS (
+             SELECT id
+             FROM page
+             WHERE id = ?
+             UNION ALL
+             SELECT p.id
+             FROM page p
+             JOIN CTE ON p.parent_id = CTE.id
+             WHERE p.deleted_at IS NULL OR ?
+           )
+           SELECT id AS int FROM CTE
+"#,
+        );
+        connection
+            .run(move |c| {
+                query
+                    .bind::<Integer, _>(root_id)
+                    .bind::<Bool, _>(include_trashed)
+                    .load::<IntegerContainer>(c)
+                    .expect("Database error")
+            })
+            .await
+            .into_iter()
+            .filter_map(|container| container.int)
+            .collect()
+    }
+
+    /// Lists the deleted subtrees in the trash, most recently deleted first.
+    pub async fn trash(connection: &PersistDatabase) -> Vec<TrashedPage> {
+        let trashed = connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                page.filter(deleted_at.is_not_null())
+                    .order(deleted_at.desc())
+                    .load::<Page>(c)
+                    .expect("Database error")
+            })
+            .await;
+        let paths = Self::all_paths(connection).await;
+
+        trashed
+            .iter()
+            .filter(|trashed_page| trashed_page.trash_root_id == trashed_page.id)
+            .map(|root| TrashedPage {
+                page: root.clone(),
+                path: paths.get(&root.id.unwrap()).cloned().unwrap_or_default(),
+                page_count: trashed
+                    .iter()
+                    .filter(|trashed_page| trashed_page.trash_root_id == root.id)
+                    .count(),
+            })
+            .collect()
+    }
+
+    /// Takes a deleted subtree out of the trash, back under its original parent. Returns
+    /// None unless `trash_root` is the root of a trashed subtree.
+    pub async fn restore_from_trash(
+        connection: &PersistDatabase,
+        memory_connection: &MemoryDatabase,
+        state: &State<ManagedState>,
+        trash_root: i32,
+    ) -> Option<Result<(), &'static str>> {
+        let root = Self::trashed_root(connection, trash_root).await?;
+
+        let siblings = connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                let query = page.filter(id.ne(trash_root)).into_boxed();
+                let query = match root.parent_id {
+                    Some(root_parent_id) => {
+                        query.filter(id.eq(root_parent_id).or(parent_id.eq(root_parent_id)))
+                    }
+                    None => query.filter(parent_id.is_null()),
+                };
+                query.load::<Page>(c).expect("Database error")
+            })
+            .await;
+
+        for sibling in siblings {
+            if sibling.id == root.parent_id {
+                if sibling.deleted_at.is_some() {
+                    return Some(Err("The page it was under is in the trash; restore that first."));
+                }
+            } else if sibling.slug == root.slug && sibling.deleted_at.is_none() {
+                return Some(Err("Another page under the same parent has taken its slug."));
+            }
+        }
+
+        connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                diesel::update(page)
+                    .filter(trash_root_id.eq(trash_root))
+                    .set((deleted_at.eq(None::<i64>), trash_root_id.eq(None::<i32>)))
+                    .execute(c)
+                    .expect("Failed to restore page.")
+            })
+            .await;
+
+        SearchResult::rebuild(connection, memory_connection).await;
+        state.nav.reload(Self::all_in_tree(connection).await);
+
+        Some(Ok(()))
+    }
+
+    /// The root of a subtree in the trash, by its id; None for any other page.
+    async fn trashed_root(connection: &PersistDatabase, trash_root: i32) -> Option<Page> {
+        connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                page.filter(id.eq(trash_root))
+                    .filter(deleted_at.is_not_null())
+                    .filter(trash_root_id.eq(trash_root))
+                    .first::<Page>(c)
+                    .optional()
+                    .expect("Database error")
+            })
+            .await
+    }
+
+    /// Deletes a subtree in the trash for good, along with its revisions and comments.
+    /// Returns None, purging nothing, unless `trash_root` is the root of a trashed subtree.
+    pub async fn purge(
+        connection: &PersistDatabase,
+        memory_connection: &MemoryDatabase,
+        nav: &NavTree,
+        trash_root: i32,
+    ) -> Option<()> {
+        Self::trashed_root(connection, trash_root).await?;
+
+        // pages trashed on their own before their parent are purged with it, but nothing
+        // that is live
+        let subtree_ids = Self::subtree_ids(connection, trash_root, true).await;
+        let subtree_ids = connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                page.filter(id.eq_any(subtree_ids))
+                    .filter(deleted_at.is_not_null())
+                    .select(id)
+                    .load::<Option<i32>>(c)
+                    .expect("Database error")
+            })
+            .await
+            .into_iter()
+            .flatten()
+            .collect::<Vec<i32>>();
+        let purged_ids = subtree_ids.clone();
+
+        connection
+            .run(move |c| {
+                diesel::delete(comment::table)
+                    .filter(comment::page_id.eq_any(subtree_ids.clone()))
+                    .execute(c)
+                    .expect("Failed to delete comments.");
+                diesel::delete(rejected_comment::table)
+                    .filter(rejected_comment::page_id.eq_any(subtree_ids.clone()))
+                    .execute(c)
+                    .expect("Failed to delete rejected comments.");
+                diesel::delete(retention_policy::table)
+                    .filter(retention_policy::page_id.eq_any(subtree_ids.clone()))
+                    .execute(c)
+                    .expect("Failed to delete retention policies.");
+                diesel::delete(page_redirect::table)
+                    .filter(page_redirect::page_id.eq_any(subtree_ids.clone()))
+                    .execute(c)
+                    .expect("Failed to delete page redirects.");
+                diesel::delete(page_revision::table)
+                    .filter(page_revision::page_id.eq_any(subtree_ids.clone()))
+                    .execute(c)
+                    .expect("Failed to delete page_revision.");
+                diesel::delete(page::table)
+                    .filter(page::id.eq_any(subtree_ids))
+                    .execute(c)
+                    .expect("Failed to delete page.");
+            })
+            .await;
+
+        // trashing already took the subtree out of both, but neither may keep a purged page
+        nav.remove_subtree(trash_root);
+        for purged_id in purged_ids {
+            memory_connection
+                .run(move |c| {
+                    let query = sql_query("DELETE FROM search WHERE id = ?");
+                    query
+                        .bind::<Integer, _>(purged_id)
+                        .execute(c)
+                        .expect("Database error");
+                })
+                .await;
+        }
+
+        Some(())
+    }
+
+    /// Purges every subtree that has been in the trash for longer than `days`. Returns how
+    /// many were purged.
+    pub async fn purge_expired_trash(
+        connection: &PersistDatabase,
+        memory_connection: &MemoryDatabase,
+        nav: &NavTree,
+        days: i64,
+    ) -> usize {
+        let cutoff = Utc::now().timestamp_millis() - days * 24 * 60 * 60 * 1000;
+        let expired = connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                page.filter(deleted_at.lt(cutoff))
+                    .filter(trash_root_id.eq(id))
+                    .select(id)
+                    .load::<Option<i32>>(c)
+                    .expect("Database error")
+            })
+            .await;
+
+        let mut purged = 0;
+        for trash_root in expired.iter() {
+            if Self::purge(connection, memory_connection, nav, trash_root.unwrap())
+                .await
+                .is_some()
+            {
+                purged += 1;
+            }
+        }
+        purged
+    }
+
+    /// Paths of every page by id, as `/`-separated slugs starting from the root.
+    pub async fn all_paths(connection: &PersistDatabase) -> HashMap<i32, String> {
+        connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                page.select((id, path))
+                    .load::<(Option<i32>, String)>(c)
+                    .expect("Database error")
+            })
+            .await
+            .into_iter()
+            .filter_map(|(page_id, page_path)| page_id.map(|page_id| (page_id, page_path)))
+            .collect()
+    }
+
+    /// Every page outside the trash, for the nav tree kept in `ManagedState`.
+    pub async fn all_in_tree(connection: &PersistDatabase) -> Vec<Page> {
+        connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                page.filter(deleted_at.is_null())
+                    .load::<Page>(c)
+                    .expect("Database error")
+            })
+            .await
+    }
+
+    pub async fn try_from_path(path: &PathBuf, connection: &PersistDatabase) -> Option<Self> {
+        let path = path.to_str().unwrap().to_string();
+        let path_spec = if path != "" {
+            format!("/{}", path)
+        } else {
+            path
+        };
+        connection
+            .run(move |c| {
+                use crate::schema::page::dsl::*;
+                page.filter(crate::schema::page::path.eq(path_spec))
+                    .filter(deleted_at.is_null())
+                    .first::<Page>(c)
+                    .optional()
+                    .expect("Database error finding page")
+            })
+            .await
+    }
+}
+
+#[derive(
+    Queryable, QueryableByName, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone,
+)]
+#[diesel(primary_key(id))]
+#[diesel(table_name = admin)]
+pub struct Admin {
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub id: Option<i32>,
+    #[diesel(sql_type = Text)]
+    pub username: String,
+    #[diesel(sql_type = Text)]
+    pub password_hash: String,
+}
+
+#[derive(
+    Queryable, QueryableByName, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone,
+)]
+#[diesel(primary_key(id))]
+#[diesel(table_name = commenter)]
+pub struct Commenter {
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub id: Option<i32>,
+    #[diesel(sql_type = Text)]
+    pub alias: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub password_hash: Option<String>,
+}
+
+#[derive(
+    Queryable, QueryableByName, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone,
+)]
+#[diesel(primary_key(id))]
+#[diesel(table_name = comment)]
+pub struct Comment {
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub id: Option<i32>,
+    #[diesel(sql_type = Integer)]
+    pub commenter_id: i32,
+    #[diesel(sql_type = Integer)]
+    pub page_id: i32,
+    #[diesel(sql_type = Text)]
+    pub text: String,
+    #[diesel(sql_type = Text)]
+    pub status: String,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub parent_id: Option<i32>,
+    #[diesel(sql_type = Text)]
+    pub html_content: String,
+}
+
+impl Commenter {
+    /// Finds or creates the commenter a new comment is posted as.
+    ///
+    /// A blank alias posts anonymously. An alias with a secret claims that alias, and later
+    /// comments under it must present the same secret; an alias without a secret is accepted
+    /// unverified, unless somebody has already claimed it.
+    pub async fn resolve(
+        connection: &PersistDatabase,
+        alias: String,
+        secret: String,
+    ) -> Result<i32, CommentRejection> {
+        let alias = alias.trim().to_string();
+
+        let new_commenter = if alias == "" {
+            Commenter {
+                id: None,
+                alias: "Anonymous".to_string(),
+                password_hash: None,
+            }
+        } else {
+            let claimed_alias = alias.clone();
+            let claimed = connection
+                .run(move |c| {
+                    use crate::schema::commenter::dsl::*;
+                    commenter
+                        .filter(crate::schema::commenter::dsl::alias.eq(claimed_alias))
+                        .filter(password_hash.is_not_null())
+                        .first::<Commenter>(c)
+                        .optional()
+                        .expect("Database error finding commenter")
+                })
+                .await;
+
+            let secret_hash = if secret == "" {
+                None
+            } else {
+                Some(hash_password(&format!("{}:{}", alias, secret)))
+            };
+
+            match claimed {
+                Some(claimed) if claimed.password_hash == secret_hash => {
+                    return Ok(claimed.id.unwrap());
+                }
+                Some(_) => return Err(CommentRejection::AliasClaimed),
+                None => Commenter {
+                    id: None,
+                    alias: alias,
+                    password_hash: secret_hash,
+                },
+            }
         };
 
         connection
             .run(move |c| {
-                use crate::schema::page::dsl::*;
-                diesel::update(page)
-                    .filter(id.eq(to_edit.id))
-                    .set(&edited)
+                diesel::insert_into(crate::schema::commenter::dsl::commenter)
+                    .values(new_commenter)
                     .execute(c)
-                    .expect("Failed to update page from path")
+                    .expect("Error saving new commenter");
             })
             .await;
 
-        connection
+        // HACK: We do this because diesel does not support RETURNING for Sqlite Backend
+        let commenter_id: Option<i32> = connection
             .run(move |c| {
-                diesel::insert_into(crate::schema::page_revision::dsl::page_revision)
-                    .values(new_revision)
-                    .execute(c)
-                    .expect("Error saving page revision");
+                let query = sql_query("SELECT last_insert_rowid() AS int");
+                let binding = query.load::<IntegerContainer>(c).expect("Database error");
+                binding.first().expect("Database error").int
             })
             .await;
 
-        let _cloned_path = edit_path.clone();
+        Ok(commenter_id.unwrap())
+    }
+}
+
+/// Why a comment submission was turned away.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum CommentRejection {
+    AliasClaimed,
+    RateLimited,
+    Honeypot,
+    FormExpired,
+    TooFast,
+    ProofOfWork,
+}
 
-        memory_connection
-        .run(move |c| {
-	    let query = sql_query("UPDATE search SET path=?, title=?, markdown_content=?, sidebar_markdown_content=? WHERE id = ?");
-	    query
-        .bind::<Text, _>(edit_path.display().to_string())
-        .bind::<Text, _>(edit_page_info.title.clone())
-        .bind::<Text, _>(edit_page_info.markdown_content.clone())
-        .bind::<Text, _>(edit_page_info.sidebar_markdown_content.clone())
-        .bind::<Nullable<Integer>, _>(to_edit.id)
-        .execute(c).expect("Database error");
-	}).await;
+impl CommentRejection {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            CommentRejection::AliasClaimed => {
+                "That alias has been claimed. Enter its secret to comment under it."
+            }
+            CommentRejection::RateLimited => {
+                "Too many comments from your address. Try again later."
+            }
+            CommentRejection::Honeypot => "The comment form was filled in incorrectly.",
+            CommentRejection::FormExpired => {
+                "The comment form expired. Reload the page and try again."
+            }
+            CommentRejection::TooFast => "The comment was submitted too quickly. Try again.",
+            CommentRejection::ProofOfWork => {
+                "The comment form's challenge was not solved. Reload the page and try again."
+            }
+        }
     }
+}
+
+/// How much of a rejected comment's alias and text is logged.
+const REJECTED_ALIAS_CHARS: usize = 100;
+const REJECTED_TEXT_CHARS: usize = 2000;
+/// How many rejected comments are logged before the oldest are dropped.
+const REJECTED_COMMENTS_KEPT: i32 = 1000;
+
+#[derive(Queryable, QueryableByName, Insertable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(primary_key(id))]
+#[diesel(table_name = rejected_comment)]
+pub struct RejectedComment {
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub id: Option<i32>,
+    #[diesel(sql_type = Integer)]
+    pub page_id: i32,
+    #[diesel(sql_type = Text)]
+    pub iso_time: String,
+    #[diesel(sql_type = Text)]
+    pub ip: String,
+    #[diesel(sql_type = Text)]
+    pub alias: String,
+    #[diesel(sql_type = Text)]
+    pub text: String,
+    #[diesel(sql_type = Text)]
+    pub reason: String,
+}
+
+/// A rejected submission as listed for admins, along with the page it was aimed at.
+#[derive(QueryableByName, Debug, Serialize, Deserialize, Clone)]
+pub struct LoggedRejection {
+    #[diesel(sql_type = Text)]
+    pub path: String,
+    #[diesel(sql_type = Text)]
+    pub title: String,
+    #[diesel(sql_type = Text)]
+    pub iso_time: String,
+    #[diesel(sql_type = Text)]
+    pub ip: String,
+    #[diesel(sql_type = Text)]
+    pub alias: String,
+    #[diesel(sql_type = Text)]
+    pub text: String,
+    #[diesel(sql_type = Text)]
+    pub reason: String,
+}
+
+impl RejectedComment {
+    pub async fn log(
+        connection: &PersistDatabase,
+        target_page_id: i32,
+        ip: Option<IpAddr>,
+        comment_info: &CommentInfo,
+        rejection: CommentRejection,
+    ) {
+        // a flood is what the rate limit is for, and logging it would only pass it on
+        if rejection == CommentRejection::RateLimited {
+            return;
+        }
+
+        let rejected = RejectedComment {
+            id: None,
+            page_id: target_page_id,
+            iso_time: Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            ip: ip.map_or("unknown".to_string(), |ip| ip.to_string()),
+            alias: comment_info.alias.chars().take(REJECTED_ALIAS_CHARS).collect(),
+            text: comment_info.text.chars().take(REJECTED_TEXT_CHARS).collect(),
+            reason: format!("{:?}", rejection),
+        };
 
-    pub async fn delete(self, connection: &PersistDatabase, memory_connection: &MemoryDatabase) {
         connection
             .run(move |c| {
-                use crate::schema::page::dsl::*;
-                diesel::delete(page)
-                    .filter(crate::schema::page::id.eq(self.id))
+                use crate::schema::rejected_comment::dsl::*;
+                diesel::insert_into(rejected_comment)
+                    .values(rejected)
                     .execute(c)
-                    .expect("Failed to delete page.")
+                    .expect("Error saving rejected comment");
+
+                let newest_id = rejected_comment
+                    .select(diesel::dsl::max(id))
+                    .first::<Option<i32>>(c)
+                    .expect("Database error finding rejected comments");
+                if let Some(newest_id) = newest_id {
+                    diesel::delete(rejected_comment)
+                        .filter(id.le(newest_id - REJECTED_COMMENTS_KEPT))
+                        .execute(c)
+                        .expect("Failed to delete rejected comments");
+                }
             })
             .await;
+    }
 
-        // Cascade delete should take care of children TODO and page revisions
-
-        memory_connection
+    pub async fn recent(connection: &PersistDatabase) -> Vec<LoggedRejection> {
+        let query = sql_query(
+            r#"
+           SELECT page.path, page.title, r.iso_time, r.ip, r.alias, r.text, r.reason
+           FROM rejected_comment r
+           JOIN page ON r.page_id = page.id
+           ORDER BY r.id DESC
+           LIMIT 200
+"#,
+        );
+        connection
             .run(move |c| {
-                let query = sql_query("DELETE FROM search WHERE id = ?");
                 query
-                    .bind::<Nullable<Integer>, _>(self.id)
-                    .execute(c)
-                    .expect("Database error");
+                    .load::<LoggedRejection>(c)
+                    .expect("Database error finding rejected comments")
             })
-            .await;
+            .await
     }
+}
+
+/// Moderation state of a comment. Only approved comments are shown under a page.
+#[derive(FromFormField, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CommentStatus {
+    Pending,
+    Approved,
+    Rejected,
+    Spam,
+}
 
-    pub async fn build_nav_element(connection: &PersistDatabase, path: &PathBuf) -> String {
-        enum Padding {
-            Blank,
-            Bar,
+impl CommentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommentStatus::Pending => "pending",
+            CommentStatus::Approved => "approved",
+            CommentStatus::Rejected => "rejected",
+            CommentStatus::Spam => "spam",
         }
-        let tree_source = connection
-            .run(move |c| {
-                use crate::schema::page::dsl::*;
-                page.select((crate::schema::page::dsl::id, parent_id, slug))
-                    .load::<(Option<i32>, Option<i32>, String)>(c)
-                    .expect("Database error")
-            })
-            .await;
+    }
+}
+
+/// A comment joined with the alias of its commenter, as listed under a page.
+#[derive(QueryableByName, Debug, Serialize, Deserialize, Clone)]
+pub struct PageComment {
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub id: Option<i32>,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub parent_id: Option<i32>,
+    #[diesel(sql_type = Text)]
+    pub alias: String,
+    #[diesel(sql_type = Text)]
+    pub html_content: String,
+    #[diesel(sql_type = Integer)]
+    pub depth: i32,
+    #[diesel(sql_type = Bool)]
+    pub verified: bool,
+}
 
-        // build a tree structure
+/// A comment together with its (recursively nested) replies.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommentThread {
+    pub comment: PageComment,
+    pub replies: Vec<CommentThread>,
+}
 
-        let mut tree = TreeBuilder::new().with_root("".to_owned()).build();
-        let root_id = tree.root_id().expect("root doesn't exist");
+/// A comment as listed in the admin moderation queue, along with the page it was left on.
+#[derive(QueryableByName, Debug, Serialize, Deserialize, Clone)]
+pub struct QueuedComment {
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub id: Option<i32>,
+    #[diesel(sql_type = Text)]
+    pub path: String,
+    #[diesel(sql_type = Text)]
+    pub title: String,
+    #[diesel(sql_type = Text)]
+    pub alias: String,
+    #[diesel(sql_type = Text)]
+    pub text: String,
+    #[diesel(sql_type = Text)]
+    pub status: String,
+}
 
-        let mut tree_map = HashMap::new();
+impl Comment {
+    pub async fn create_and_insert(
+        target_page_id: i32,
+        comment_info: CommentInfo,
+        state: &State<ManagedState>,
+        connection: &PersistDatabase,
+    ) -> Result<(), CommentRejection> {
+        let parent_id = match comment_info.parent_id {
+            Some(reply_to) => {
+                Self::resolve_reply_parent(connection, target_page_id, reply_to, state).await
+            }
+            None => None,
+        };
 
-        for (ret_id, ret_parent_id, ret_slug) in tree_source {
-            if ret_slug == "" {
-                tree_map.insert(ret_id, root_id);
-            } else if ret_parent_id == None {
-                let mut root = tree.get_mut(root_id).unwrap();
-                let slug_node = root.append(ret_slug);
+        let commenter_id =
+            Commenter::resolve(connection, comment_info.alias, comment_info.secret).await?;
 
-                let slug_node_id = slug_node.node_id();
-                tree_map.insert(ret_id, slug_node_id);
-            } else {
-                let tree_parent_id = tree_map.get(&ret_parent_id).unwrap();
-                let mut tree_parent = tree.get_mut(*tree_parent_id).unwrap();
+        let comment = Comment {
+            id: None,
+            commenter_id: commenter_id,
+            page_id: target_page_id,
+            html_content: comment_md2html(
+                comment_info.text.clone(),
+                state.comment_parser_options,
+            ),
+            text: comment_info.text,
+            status: CommentStatus::Pending.as_str().to_string(),
+            parent_id: parent_id,
+        };
 
-                let slug_node = tree_parent.append(ret_slug);
+        connection
+            .run(move |c| {
+                diesel::insert_into(crate::schema::comment::dsl::comment)
+                    .values(comment)
+                    .execute(c)
+                    .expect("Error saving new comment");
+            })
+            .await;
 
-                let slug_node_id = slug_node.node_id();
-                tree_map.insert(ret_id, slug_node_id);
-            }
-        }
+        Ok(())
+    }
 
-        // traverse tree to emit nav element
-
-        let mut nav_element = String::from("");
-        let acc_path = String::from("");
-        let binding = path.to_str().unwrap().to_string();
-        let mut segments: Vec<&str> = binding.split('/').collect();
-        segments.insert(0, ""); // root is on every path
-
-        nav_element.push_str("<ul>");
-        process_node(
-            &tree,
-            root_id,
-            root_id,
-            &mut nav_element,
-            acc_path,
-            &mut segments,
-            false,
-            &mut Vec::new(),
+    /// Picks the comment a reply is attached to. Replies to comments on other pages are
+    /// posted top-level, and replies that would nest past `comment_max_depth` are attached
+    /// to the nearest ancestor that still has room.
+    async fn resolve_reply_parent(
+        connection: &PersistDatabase,
+        target_page_id: i32,
+        reply_to: i32,
+        state: &State<ManagedState>,
+    ) -> Option<i32> {
+        let query = sql_query(
+            r#"
+             WITH RECURSIVE CTE AS (
+             SELECT id, parent_id, page_id, 0 AS depth
+             FROM comment
+             WHERE id = ?
+             UNION ALL
+             SELECT c.id, c.parent_id, c.page_id, CTE.depth + 1
+             FROM comment c
+             JOIN CTE ON c.id = CTE.parent_id
+           )
+           SELECT id AS int FROM CTE WHERE page_id = ? ORDER BY depth;
+"#,
         );
-        nav_element.push_str("</ul>");
-
-        fn process_node(
-            tree: &Tree<String>,
-            current_node_id: NodeId,
-            root_id: NodeId,
-            nav_element: &mut String,
-            acc_path: String,
-            segments: &mut Vec<&str>,
-            is_last_child: bool,
-            prev: &mut Vec<Padding>,
-        ) {
-            let current_node = tree.get(current_node_id).unwrap();
-            nav_element.push_str("<li>");
-            if !prev.is_empty() {
-                for i in 0..(prev.len() - 1) {
-                    match prev[i] {
-                        Padding::Blank => nav_element.push_str("&nbsp;&nbsp;&nbsp;"),
-                        Padding::Bar => nav_element.push_str("|&nbsp;&nbsp;"),
-                    }
-                }
-                if is_last_child {
-                    nav_element.push_str("└──");
-                } else {
-                    nav_element.push_str("├──");
-                }
-            }
+        // ancestors of the comment being replied to, starting with itself
+        let ancestors: Vec<Option<i32>> = connection
+            .run(move |c| {
+                query
+                    .bind::<Integer, _>(reply_to)
+                    .bind::<Integer, _>(target_page_id)
+                    .load::<IntegerContainer>(c)
+                    .expect("Database error finding parent comment")
+            })
+            .await
+            .into_iter()
+            .map(|container| container.int)
+            .collect();
 
-            let children: Vec<NodeRef<String>> = current_node.children().collect();
-            let new_seg = if children.len() != 0 {
-                format!("{}/", current_node.data())
-            } else {
-                format!("{}", current_node.data())
-            };
-            let new_path = format!("{}{}", acc_path, new_seg);
-            nav_element
-                .push_str(format!("<a href=\"/pages{}\">{}</a>", new_path, new_seg).as_str());
-
-            if children.len() != 0 && segments.len() != 0 && current_node.data() == segments[0] {
-                segments.remove(0);
-                nav_element.push_str("<ul>");
-
-                let mut children = current_node.children().peekable();
-
-                while let Some(child) = children.next() {
-                    if children.peek().is_some() {
-                        prev.push(Padding::Bar);
-                        process_node(
-                            tree,
-                            child.node_id(),
-                            root_id,
-                            nav_element,
-                            new_path.clone(),
-                            segments,
-                            false,
-                            prev,
-                        );
-                        prev.pop();
-                    } else {
-                        prev.push(Padding::Blank);
-                        process_node(
-                            tree,
-                            child.node_id(),
-                            root_id,
-                            nav_element,
-                            new_path.clone(),
-                            segments,
-                            true,
-                            prev,
-                        );
-                        prev.pop();
-                    }
-                }
-                nav_element.push_str("</ul>");
-            }
-            nav_element.push_str("</li>");
+        if ancestors.is_empty() {
+            return None;
         }
 
-        nav_element
+        // the parent at index i sits at depth (len - 1 - i), so the reply lands at (len - i)
+        let max_depth = state.comment_max_depth.max(1) as usize;
+        let skip = ancestors.len().saturating_sub(max_depth - 1);
+        ancestors.get(skip).copied().flatten()
     }
 
-    pub async fn from_path(path: &PathBuf, connection: &PersistDatabase) -> Self {
+    pub async fn for_page(
+        connection: &PersistDatabase,
+        target_page_id: i32,
+    ) -> Vec<CommentThread> {
         let query = sql_query(
             r#"
              WITH RECURSIVE CTE AS (
-             SELECT id, slug AS path
-             FROM page
-             WHERE parent_id IS NULL
+             SELECT id, parent_id, commenter_id, html_content, 0 AS depth
+             FROM comment
+             WHERE page_id = ? AND parent_id IS NULL AND status = ?
              UNION ALL
-             SELECT p.id, path || '/' || slug
-             FROM page p
-             JOIN CTE ON p.parent_id = CTE.id
+             SELECT c.id, c.parent_id, c.commenter_id, c.html_content, CTE.depth + 1
+             FROM comment c
+             JOIN CTE ON c.parent_id = CTE.id
+             WHERE c.status = ?
            )
-           SELECT * FROM page WHERE id = (
-           SELECT id FROM CTE WHERE path = ?
-           );
+           SELECT CTE.id, CTE.parent_id, commenter.alias, CTE.html_content, CTE.depth,
+                  commenter.password_hash IS NOT NULL AS verified
+           FROM CTE
+           JOIN commenter ON CTE.commenter_id = commenter.id
+           ORDER BY CTE.id;
 "#,
         );
-        let path = path.to_str().unwrap().to_string();
-        let path_spec = if path != "" {
-            format!("/{}", path)
-        } else {
-            path
-        };
-        let binding = connection
+        let approved = CommentStatus::Approved.as_str();
+        let flat = connection
             .run(move |c| {
                 query
-                    .bind::<Text, _>(path_spec)
-                    .load::<Page>(c)
-                    .expect("Database error finding page")
+                    .bind::<Integer, _>(target_page_id)
+                    .bind::<Text, _>(approved)
+                    .bind::<Text, _>(approved)
+                    .load::<PageComment>(c)
+                    .expect("Database error finding comments")
             })
             .await;
-        let child = binding.first().expect("No such page found");
-        child.clone()
+
+        let mut by_parent: HashMap<Option<i32>, Vec<PageComment>> = HashMap::new();
+        for page_comment in flat {
+            by_parent
+                .entry(page_comment.parent_id)
+                .or_insert_with(Vec::new)
+                .push(page_comment);
+        }
+
+        fn build_threads(
+            parent_id: Option<i32>,
+            by_parent: &mut HashMap<Option<i32>, Vec<PageComment>>,
+        ) -> Vec<CommentThread> {
+            let children = by_parent.remove(&parent_id).unwrap_or_default();
+            children
+                .into_iter()
+                .map(|child| {
+                    let replies = build_threads(child.id, by_parent);
+                    CommentThread {
+                        comment: child,
+                        replies: replies,
+                    }
+                })
+                .collect()
+        }
+
+        build_threads(None, &mut by_parent)
     }
-}
 
-#[derive(
-    Queryable, QueryableByName, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone,
-)]
-#[diesel(primary_key(id))]
-#[diesel(table_name = admin)]
-pub struct Admin {
-    #[diesel(sql_type = Nullable<Integer>)]
-    pub id: Option<i32>,
-    #[diesel(sql_type = Text)]
-    pub username: String,
-    #[diesel(sql_type = Text)]
-    pub password_hash: String,
+    pub async fn moderation_queue(
+        connection: &PersistDatabase,
+        status: CommentStatus,
+    ) -> Vec<QueuedComment> {
+        let query = sql_query(
+            r#"
+           SELECT comment.id, page.path, page.title, commenter.alias, comment.text, comment.status
+           FROM comment
+           JOIN commenter ON comment.commenter_id = commenter.id
+           JOIN page ON comment.page_id = page.id
+           WHERE comment.status = ?
+           ORDER BY comment.id
+"#,
+        );
+        connection
+            .run(move |c| {
+                query
+                    .bind::<Text, _>(status.as_str())
+                    .load::<QueuedComment>(c)
+                    .expect("Database error finding comments")
+            })
+            .await
+    }
+
+    pub async fn set_status(
+        connection: &PersistDatabase,
+        comment_ids: Vec<i32>,
+        new_status: CommentStatus,
+    ) {
+        connection
+            .run(move |c| {
+                use crate::schema::comment::dsl::*;
+                diesel::update(comment)
+                    .filter(id.eq_any(comment_ids))
+                    .set(status.eq(new_status.as_str()))
+                    .execute(c)
+                    .expect("Failed to update comment status")
+            })
+            .await;
+    }
 }
 
 #[derive(Queryable, QueryableByName, Debug, Serialize, Deserialize, Clone)]
@@ -566,20 +2592,15 @@ impl SearchResult {
     ) {
         let query = sql_query(
             r#"
-             WITH RECURSIVE CTE AS (
-             SELECT id, slug AS path, title--, markdown_content, sidebar_markdown_content
-             FROM page
-             WHERE parent_id IS NULL
-             UNION ALL
-             SELECT p.id, path || '/' || p.slug, p.title--, p.markdown_content, p.sidebar_markdown_content
-             FROM page p
-             JOIN CTE ON p.parent_id = CTE.id
-           )
-           SELECT * FROM CTE
+           SELECT page.id, page.path, page.title, page_revision.markdown_content,
+                  page_revision.sidebar_markdown_content
+           FROM page
            LEFT JOIN page_revision
 -- https://stackoverflow.com/questions/725153/most-recent-record-in-a-left-join
-           ON CTE.id = page_revision.page_id
-           AND page_revision.unix_time = (SELECT MAX(unix_time) FROM page_revision z WHERE z.page_id = page_revision.page_id);
+           ON page.id = page_revision.page_id
+           AND page_revision.is_draft = 0
+           AND page_revision.unix_time = (SELECT MAX(unix_time) FROM page_revision z WHERE z.page_id = page_revision.page_id AND z.is_draft = 0)
+           WHERE page.deleted_at IS NULL;
 "#,
         );
 
@@ -606,6 +2627,41 @@ impl SearchResult {
         }
     }
 
+    /// Replaces the searchable content of a page with that of its newly published revision.
+    pub async fn update_content(
+        memory_connection: &MemoryDatabase,
+        target_page_id: i32,
+        markdown_content: String,
+        sidebar_markdown_content: String,
+    ) {
+        memory_connection
+            .run(move |c| {
+                let query = sql_query(
+                    "UPDATE search SET markdown_content=?, sidebar_markdown_content=? WHERE id = ?",
+                );
+                query
+                    .bind::<Text, _>(markdown_content)
+                    .bind::<Text, _>(sidebar_markdown_content)
+                    .bind::<Integer, _>(target_page_id)
+                    .execute(c)
+                    .expect("Database error");
+            })
+            .await;
+    }
+
+    /// Repopulates the search table from scratch, after pages have come back from the trash.
+    pub async fn rebuild(connection: &PersistDatabase, memory_connection: &MemoryDatabase) {
+        memory_connection
+            .run(move |c| {
+                sql_query("DELETE FROM search")
+                    .execute(c)
+                    .expect("Database error");
+            })
+            .await;
+
+        Self::populate_with_revisions(connection, memory_connection).await;
+    }
+
     pub async fn run_search(memory_connection: &MemoryDatabase, query: String) -> Vec<Self> {
         let search_results = sql_query(
             r#"SELECT id, path, snippet(search, 2, '<span class="highlight">', '</span>', '...', 64) AS "title", snippet(search, 3, '<span class="highlight">', '</span>', '...', 64) AS "markdown_content", snippet(search, 4, '<span class="highlight">', '</span>', '...', 64) AS "sidebar_markdown_content" FROM search WHERE search MATCH '{title markdown_content sidebar_markdown_content}: ' || ? "#,
@@ -0,0 +1,316 @@
+use crate::models::{ChildSort, Page};
+use serde::Serialize;
+use std::{collections::HashMap, path::PathBuf, sync::RwLock};
+
+/// The page tree behind the nav, kept in memory so that a page view doesn't have to load
+/// the whole `page` table. Pages in the trash are left out.
+pub struct NavTree {
+    index: RwLock<NavIndex>,
+    /// How many levels below the root are listed, if limited.
+    max_depth: Option<usize>,
+    theme: NavTheme,
+}
+
+/// How `nav_macros` draws the tree.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NavTheme {
+    /// Branches drawn with `├──`/`└──`, as in the output of `tree`.
+    Ascii,
+    /// Plain nested lists.
+    Plain,
+}
+
+/// The nav for one page, for templates to render with `nav_macros::nav`.
+#[derive(Serialize, Debug)]
+pub struct Nav {
+    pub theme: NavTheme,
+    pub root: NavNode,
+    /// From the root down to the current page.
+    pub breadcrumbs: Vec<Breadcrumb>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Breadcrumb {
+    pub title: String,
+    pub href: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NavNode {
+    pub title: String,
+    /// The slug, with a trailing `/` when the page has children.
+    pub label: String,
+    pub href: String,
+    /// Levels below the root; the root is at 0.
+    pub depth: usize,
+    /// For each ancestor between the root and this node, whether its branch goes on past
+    /// this node, i.e. whether the ASCII theme draws a `|` in that column.
+    pub guides: Vec<bool>,
+    pub is_last: bool,
+    /// Only filled in along the branch leading to the current page.
+    pub children: Vec<NavNode>,
+}
+
+#[derive(Default)]
+struct NavIndex {
+    pages: HashMap<i32, Page>,
+    /// Child ids by parent id, each in their parent's order.
+    children: HashMap<Option<i32>, Vec<i32>>,
+}
+
+impl NavIndex {
+    /// Puts the children of `parent_id` back in the order their parent asks for.
+    fn sort_children(&mut self, parent_id: Option<i32>) {
+        let child_sort = parent_id
+            .and_then(|parent_id| self.pages.get(&parent_id))
+            .map_or(ChildSort::Manual.as_str().to_string(), |parent| {
+                parent.child_sort.clone()
+            });
+        let mut siblings: Vec<Page> = self
+            .children
+            .get(&parent_id)
+            .map(|child_ids| child_ids.iter().map(|id| self.pages[id].clone()).collect())
+            .unwrap_or_default();
+        Page::sort_siblings(&mut siblings, &child_sort);
+        self.children.insert(
+            parent_id,
+            siblings.iter().map(|sibling| sibling.id.unwrap()).collect(),
+        );
+    }
+
+    fn detach(&mut self, page_id: i32, parent_id: Option<i32>) {
+        if let Some(siblings) = self.children.get_mut(&parent_id) {
+            siblings.retain(|sibling_id| *sibling_id != page_id);
+        }
+    }
+
+    /// Children of a node of the rendered tree, where None is its root. The root page's
+    /// children hang directly off the root, as do any other pages without a parent.
+    fn node_children(&self, node: Option<i32>) -> Vec<i32> {
+        match node {
+            Some(page_id) => self
+                .children
+                .get(&Some(page_id))
+                .cloned()
+                .unwrap_or_default(),
+            None => {
+                let mut node_children = Vec::new();
+                for top_level_id in self.children.get(&None).cloned().unwrap_or_default() {
+                    if self.pages[&top_level_id].slug == "" {
+                        node_children.extend(self.node_children(Some(top_level_id)));
+                    } else {
+                        node_children.push(top_level_id);
+                    }
+                }
+                node_children
+            }
+        }
+    }
+
+    /// The top-level page with the empty slug, whose children the nav lists at the top.
+    fn root_page(&self) -> Option<&Page> {
+        self.children.get(&None).and_then(|top_level_ids| {
+            top_level_ids
+                .iter()
+                .map(|top_level_id| &self.pages[top_level_id])
+                .find(|top_level| top_level.slug == "")
+        })
+    }
+
+    fn breadcrumbs(&self, segments: &[&str]) -> Vec<Breadcrumb> {
+        let mut breadcrumbs = vec![Breadcrumb {
+            title: self
+                .root_page()
+                .map_or(String::new(), |root| root.title.clone()),
+            href: String::from("/pages/"),
+        }];
+
+        let mut node = None;
+        let mut acc_path = String::from("/pages");
+        for segment in segments.iter().filter(|segment| **segment != "") {
+            let child = self
+                .node_children(node)
+                .into_iter()
+                .find(|child_id| self.pages[child_id].slug == *segment);
+            let child_id = match child {
+                Some(child_id) => child_id,
+                None => break,
+            };
+            acc_path = format!("{}/{}", acc_path, segment);
+            breadcrumbs.push(Breadcrumb {
+                title: self.pages[&child_id].title.clone(),
+                href: acc_path.clone(),
+            });
+            node = Some(child_id);
+        }
+        breadcrumbs
+    }
+
+    fn build_node(
+        &self,
+        node: Option<i32>,
+        acc_path: String,
+        segments: &[&str],
+        guides: &mut Vec<bool>,
+        is_last: bool,
+        max_depth: Option<usize>,
+    ) -> NavNode {
+        let (slug, title) = match node {
+            Some(page_id) => {
+                let page = &self.pages[&page_id];
+                (page.slug.as_str(), page.title.as_str())
+            }
+            None => {
+                let root_title = self.root_page().map_or("", |root| root.title.as_str());
+                ("", root_title)
+            }
+        };
+        let depth = guides.len();
+
+        let children = self.node_children(node);
+        let new_seg = if children.len() != 0 {
+            format!("{}/", slug)
+        } else {
+            format!("{}", slug)
+        };
+        let new_path = format!("{}{}", acc_path, new_seg);
+
+        let mut nav_node = NavNode {
+            title: title.to_string(),
+            label: new_seg,
+            href: format!("/pages{}", new_path),
+            depth: depth,
+            guides: guides[..depth.saturating_sub(1)].to_vec(),
+            is_last: is_last,
+            children: Vec::new(),
+        };
+
+        // only the branch leading to the current page is expanded, each node matching the
+        // path segment at its own depth
+        let within_depth = max_depth.map_or(true, |max_depth| depth < max_depth);
+        if within_depth && children.len() != 0 && segments.get(depth) == Some(&slug) {
+            let mut children = children.into_iter().peekable();
+
+            while let Some(child) = children.next() {
+                let is_last = children.peek().is_none();
+                guides.push(!is_last);
+                nav_node.children.push(self.build_node(
+                    Some(child),
+                    new_path.clone(),
+                    segments,
+                    guides,
+                    is_last,
+                    max_depth,
+                ));
+                guides.pop();
+            }
+        }
+        nav_node
+    }
+}
+
+impl NavTree {
+    pub fn new(max_depth: Option<usize>, theme: NavTheme) -> Self {
+        NavTree {
+            index: RwLock::new(NavIndex::default()),
+            max_depth: max_depth,
+            theme: theme,
+        }
+    }
+
+    /// Replaces the whole tree, as loaded by `Page::all_in_tree`.
+    pub fn reload(&self, pages: Vec<Page>) {
+        let mut index = NavIndex::default();
+        for (parent_id, siblings) in Page::sorted_children(pages) {
+            index.children.insert(
+                parent_id,
+                siblings.iter().map(|sibling| sibling.id.unwrap()).collect(),
+            );
+            for sibling in siblings {
+                index.pages.insert(sibling.id.unwrap(), sibling);
+            }
+        }
+        *self.index.write().unwrap() = index;
+    }
+
+    /// Adds a created page, or takes in the new title, slug, parent or ordering of an
+    /// existing one.
+    pub fn upsert(&self, page: &Page) {
+        let mut index = self.index.write().unwrap();
+        let page_id = page.id.unwrap();
+
+        let old_parent_id = index.pages.get(&page_id).map(|old| old.parent_id);
+        if let Some(old_parent_id) = old_parent_id {
+            index.detach(page_id, old_parent_id);
+        }
+        index
+            .children
+            .entry(page.parent_id)
+            .or_default()
+            .push(page_id);
+        index.pages.insert(page_id, page.clone());
+
+        index.sort_children(page.parent_id);
+        // the page's own child_sort may have changed
+        index.sort_children(Some(page_id));
+    }
+
+    /// Takes in a new `child_sort` for a page, along with the manual positions of its children.
+    pub fn reorder(&self, parent_id: i32, child_sort: ChildSort, positions: HashMap<i32, i32>) {
+        let mut index = self.index.write().unwrap();
+
+        if let Some(parent) = index.pages.get_mut(&parent_id) {
+            parent.child_sort = child_sort.as_str().to_string();
+        }
+        for (child_id, position) in positions {
+            if let Some(child) = index.pages.get_mut(&child_id) {
+                if child.parent_id == Some(parent_id) {
+                    child.position = position;
+                }
+            }
+        }
+        index.sort_children(Some(parent_id));
+    }
+
+    /// Drops a page and everything below it, once they have gone to the trash.
+    pub fn remove_subtree(&self, page_id: i32) {
+        let mut index = self.index.write().unwrap();
+
+        if let Some(parent_id) = index.pages.get(&page_id).map(|page| page.parent_id) {
+            index.detach(page_id, parent_id);
+        }
+
+        let mut to_remove = vec![page_id];
+        while let Some(removed_id) = to_remove.pop() {
+            index.pages.remove(&removed_id);
+            if let Some(child_ids) = index.children.remove(&Some(removed_id)) {
+                to_remove.extend(child_ids);
+            }
+        }
+    }
+
+    /// The nav for the page at `path`, expanding only the branch leading to it.
+    pub fn render(&self, path: &PathBuf) -> Nav {
+        let index = self.index.read().unwrap();
+
+        let binding = path.to_str().unwrap().to_string();
+        let mut segments: Vec<&str> = binding.split('/').collect();
+        segments.insert(0, ""); // root is on every path
+
+        let breadcrumbs = index.breadcrumbs(&segments);
+
+        Nav {
+            theme: self.theme,
+            breadcrumbs: breadcrumbs,
+            root: index.build_node(
+                None,
+                String::from(""),
+                &segments,
+                &mut Vec::new(),
+                false,
+                self.max_depth,
+            ),
+        }
+    }
+}
@@ -14,6 +14,9 @@ diesel::table! {
         commenter_id -> Integer,
         page_id -> Integer,
         text -> Text,
+        status -> Text,
+        parent_id -> Nullable<Integer>,
+        html_content -> Text,
     }
 }
 
@@ -31,6 +34,20 @@ diesel::table! {
         parent_id -> Nullable<Integer>,
         title -> Text,
         slug -> Text,
+        deleted_at -> Nullable<BigInt>,
+        trash_root_id -> Nullable<Integer>,
+        position -> Integer,
+        child_sort -> Text,
+        path -> Text,
+        show_child_index -> Bool,
+    }
+}
+
+diesel::table! {
+    page_redirect (id) {
+        id -> Nullable<Integer>,
+        old_path -> Text,
+        page_id -> Integer,
     }
 }
 
@@ -39,16 +56,59 @@ diesel::table! {
         id -> Nullable<Integer>,
         page_id -> Nullable<Integer>,
         iso_time -> Text,
-        unix_time -> Integer,
+        unix_time -> BigInt,
         html_content -> Text,
         markdown_content -> Text,
         sidebar_html_content -> Text,
         sidebar_markdown_content -> Text,
+        admin_id -> Nullable<Integer>,
+        summary -> Text,
+        is_minor -> Bool,
+        is_delta -> Bool,
+        is_draft -> Bool,
+        publish_at -> Nullable<BigInt>,
+        base_revision_id -> Nullable<Integer>,
+        publish_conflict -> Bool,
+    }
+}
+
+diesel::table! {
+    rejected_comment (id) {
+        id -> Nullable<Integer>,
+        page_id -> Integer,
+        iso_time -> Text,
+        ip -> Text,
+        alias -> Text,
+        text -> Text,
+        reason -> Text,
+    }
+}
+
+diesel::table! {
+    retention_policy (id) {
+        id -> Nullable<Integer>,
+        page_id -> Nullable<Integer>,
+        keep_all_days -> Integer,
+        keep_weekly_days -> Integer,
+        keep_monthly_days -> Nullable<Integer>,
     }
 }
 
 diesel::joinable!(comment -> commenter (commenter_id));
 diesel::joinable!(comment -> page (page_id));
+diesel::joinable!(page_redirect -> page (page_id));
+diesel::joinable!(page_revision -> admin (admin_id));
 diesel::joinable!(page_revision -> page (page_id));
+diesel::joinable!(rejected_comment -> page (page_id));
+diesel::joinable!(retention_policy -> page (page_id));
 
-diesel::allow_tables_to_appear_in_same_query!(admin, comment, commenter, page, page_revision,);
+diesel::allow_tables_to_appear_in_same_query!(
+    admin,
+    comment,
+    commenter,
+    page,
+    page_redirect,
+    page_revision,
+    rejected_comment,
+    retention_policy,
+);
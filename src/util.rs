@@ -26,7 +26,15 @@ use rocket_dyn_templates::{context, Template};
 use slab_tree::*;
 use std::{collections::HashMap, path::PathBuf};
 
-use pulldown_cmark::{html, Options, Parser};
+use chrono::{NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use crypto::{digest::Digest, sha3::Sha3};
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+use rocket::serde::json::serde_json;
+use similar::{capture_diff_slices, Algorithm, ChangeTag, DiffOp, TextDiff};
+use slugify::slugify;
+use rocket_dyn_templates::tera::{self, Value};
+use std::collections::HashSet;
 
 fn _org2html(org: String) -> String {
     let mut pandoc = pandoc::new();
@@ -48,6 +56,56 @@ fn _org2html(org: String) -> String {
     }
 }
 
+/// The current time as a full ISO-8601 datetime in UTC and as unix milliseconds, for
+/// stamping a new revision.
+pub fn revision_timestamp() -> (String, i64) {
+    let now = Utc::now();
+    (now.to_rfc3339_opts(SecondsFormat::Millis, true), now.timestamp_millis())
+}
+
+/// Builds the `datetime` template filter, which shows a unix millisecond timestamp in the
+/// configured display timezone.
+pub fn datetime_filter(
+    timezone: Tz,
+) -> impl Fn(&Value, &HashMap<String, Value>) -> tera::Result<Value> + Sync + Send {
+    move |value, _args| {
+        let millis = value
+            .as_i64()
+            .ok_or_else(|| tera::Error::msg("datetime expects unix milliseconds"))?;
+        let datetime = Utc
+            .timestamp_millis_opt(millis)
+            .single()
+            .ok_or_else(|| tera::Error::msg("datetime out of range"))?
+            .with_timezone(&timezone);
+        Ok(Value::String(datetime.format("%Y-%m-%d %H:%M:%S %Z").to_string()))
+    }
+}
+
+/// Parses a `datetime-local` form value, taken as local time in `timezone`, to unix
+/// milliseconds.
+pub fn parse_local_datetime(value: &str, timezone: Tz) -> Option<i64> {
+    let naive = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M").ok()?;
+    timezone
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|datetime| datetime.timestamp_millis())
+}
+
+/// Normalizes a slug so that it makes a single path segment, generating it from the title
+/// when left blank.
+pub fn normalize_slug(slug: &str, title: &str) -> String {
+    match slug.trim() {
+        "" => slugify!(title),
+        slug => slugify!(slug),
+    }
+}
+
+pub fn hash_password(password: &String) -> String {
+    let mut hasher = Sha3::sha3_256();
+    hasher.input_str(password);
+    hasher.result_str()
+}
+
 pub fn md2html(md: String, options: Options) -> String {
     let parser = Parser::new_ext(&md, options);
     let mut html_output = String::new();
@@ -55,6 +113,325 @@ pub fn md2html(md: String, options: Options) -> String {
     html_output
 }
 
+/// Markdown paragraph replaced by the index of a page's children when it is shown.
+pub const CHILD_INDEX_PLACEHOLDER: &str = "[[children]]";
+
+/// Plain text of the first paragraph of some markdown, for summaries. The child index
+/// placeholder doesn't count as a paragraph.
+pub fn first_paragraph(md: &str) -> String {
+    let mut text = String::new();
+    let mut in_paragraph = false;
+    for event in Parser::new(md) {
+        match event {
+            Event::Start(Tag::Paragraph) => in_paragraph = true,
+            Event::End(Tag::Paragraph) => {
+                in_paragraph = false;
+                if text.trim() == CHILD_INDEX_PLACEHOLDER {
+                    text.clear();
+                } else {
+                    break;
+                }
+            }
+            Event::Text(part) | Event::Code(part) if in_paragraph => text.push_str(&part),
+            Event::SoftBreak | Event::HardBreak if in_paragraph => text.push(' '),
+            _ => (),
+        }
+    }
+    text.trim().to_string()
+}
+
+/// Renders a visitor's comment. Raw HTML is shown as text and images are reduced to their
+/// alt text, then the result goes through an allow-list so only basic formatting and
+/// `rel="nofollow ugc"` links survive.
+pub fn comment_md2html(md: String, options: Options) -> String {
+    let parser = Parser::new_ext(&md, options).filter_map(|event| match event {
+        Event::Html(raw) => Some(Event::Text(raw)),
+        Event::Start(Tag::Image(..)) | Event::End(Tag::Image(..)) => None,
+        event => Some(event),
+    });
+    let mut html_output = String::new();
+    html::push_html(&mut html_output, parser);
+
+    let tags: HashSet<&str> = [
+        "p", "br", "em", "strong", "del", "code", "pre", "blockquote", "ul", "ol", "li", "a",
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let mut tag_attributes = HashMap::new();
+    tag_attributes.insert("a", ["href"].iter().cloned().collect::<HashSet<&str>>());
+    tag_attributes.insert("ol", ["start"].iter().cloned().collect::<HashSet<&str>>());
+    let url_schemes: HashSet<&str> = ["http", "https", "mailto"].iter().cloned().collect();
+
+    ammonia::Builder::new()
+        .tags(tags)
+        .tag_attributes(tag_attributes)
+        .generic_attributes(HashSet::new())
+        .url_schemes(url_schemes)
+        .link_rel(Some("nofollow ugc"))
+        .clean(&html_output)
+        .to_string()
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DiffSegment {
+    pub emphasized: bool,
+    pub text: String,
+}
+
+/// One line of a diff, split into segments so that the words which changed within it can
+/// be highlighted.
+#[derive(Serialize, Debug, Clone)]
+pub struct DiffLine {
+    pub tag: &'static str,
+    pub old_line: Option<usize>,
+    pub new_line: Option<usize>,
+    pub segments: Vec<DiffSegment>,
+}
+
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let diff = TextDiff::from_lines(old, new);
+    let mut lines = Vec::new();
+    for op in diff.ops() {
+        for change in diff.iter_inline_changes(op) {
+            let tag = match change.tag() {
+                ChangeTag::Equal => "equal",
+                ChangeTag::Delete => "delete",
+                ChangeTag::Insert => "insert",
+            };
+            let segments = change
+                .iter_strings_lossy()
+                .map(|(emphasized, text)| DiffSegment {
+                    emphasized: emphasized,
+                    text: text.trim_end_matches(&['\r', '\n'][..]).to_string(),
+                })
+                .collect();
+            lines.push(DiffLine {
+                tag: tag,
+                old_line: change.old_index().map(|index| index + 1),
+                new_line: change.new_index().map(|index| index + 1),
+                segments: segments,
+            });
+        }
+    }
+    lines
+}
+
+/// For each line of the last of `versions` (oldest first), the index of the version that
+/// last changed it.
+pub fn blame_lines(versions: &[&str]) -> Vec<usize> {
+    let mut owners: Vec<usize> = Vec::new();
+    let mut previous_lines: Vec<&str> = Vec::new();
+
+    for (version, text) in versions.iter().enumerate() {
+        let lines: Vec<&str> = text.split_inclusive('\n').collect();
+        let mut new_owners = vec![version; lines.len()];
+        for op in capture_diff_slices(Algorithm::Myers, &previous_lines, &lines) {
+            if let DiffOp::Equal {
+                old_index,
+                new_index,
+                len,
+            } = op
+            {
+                new_owners[new_index..new_index + len]
+                    .copy_from_slice(&owners[old_index..old_index + len]);
+            }
+        }
+        owners = new_owners;
+        previous_lines = lines;
+    }
+    owners
+}
+
+/// A run of base lines (start, end) that one side of a merge replaced with other lines.
+struct MergeChange<'a> {
+    start: usize,
+    end: usize,
+    lines: Vec<&'a str>,
+}
+
+fn merge_changes<'a>(base_lines: &[&'a str], lines: &[&'a str]) -> Vec<MergeChange<'a>> {
+    capture_diff_slices(Algorithm::Myers, base_lines, lines)
+        .into_iter()
+        .filter_map(|op| match op {
+            DiffOp::Equal { .. } => None,
+            DiffOp::Delete {
+                old_index, old_len, ..
+            } => Some(MergeChange {
+                start: old_index,
+                end: old_index + old_len,
+                lines: Vec::new(),
+            }),
+            DiffOp::Insert {
+                old_index,
+                new_index,
+                new_len,
+            } => Some(MergeChange {
+                start: old_index,
+                end: old_index,
+                lines: lines[new_index..new_index + new_len].to_vec(),
+            }),
+            DiffOp::Replace {
+                old_index,
+                old_len,
+                new_index,
+                new_len,
+            } => Some(MergeChange {
+                start: old_index,
+                end: old_index + old_len,
+                lines: lines[new_index..new_index + new_len].to_vec(),
+            }),
+        })
+        .collect()
+}
+
+/// Applies the changes of one side to the base lines from `start` to `end`.
+fn apply_merge_changes(
+    base_lines: &[&str],
+    changes: &[&MergeChange],
+    start: usize,
+    end: usize,
+) -> String {
+    let mut text = String::new();
+    let mut position = start;
+    for change in changes {
+        text.push_str(&base_lines[position..change.start].concat());
+        text.push_str(&change.lines.concat());
+        position = change.end;
+    }
+    text.push_str(&base_lines[position..end].concat());
+    text
+}
+
+/// Line-based three-way merge of `ours` and `theirs`, which were both edited from `base`.
+/// Where they changed the same lines differently, both versions are kept between conflict
+/// markers. Returns the merged text and whether there were any conflicts.
+pub fn merge3(base: &str, ours: &str, theirs: &str) -> (String, bool) {
+    let base_lines: Vec<&str> = base.split_inclusive('\n').collect();
+    let our_lines: Vec<&str> = ours.split_inclusive('\n').collect();
+    let their_lines: Vec<&str> = theirs.split_inclusive('\n').collect();
+    let our_changes = merge_changes(&base_lines, &our_lines);
+    let their_changes = merge_changes(&base_lines, &their_lines);
+
+    let mut merged = String::new();
+    let mut has_conflicts = false;
+    let mut position = 0;
+    let (mut i, mut j) = (0, 0);
+
+    while i < our_changes.len() || j < their_changes.len() {
+        let take_ours = match (our_changes.get(i), their_changes.get(j)) {
+            (Some(ours), Some(theirs)) => ours.start <= theirs.start,
+            (Some(_), None) => true,
+            _ => false,
+        };
+        let first = if take_ours {
+            &our_changes[i]
+        } else {
+            &their_changes[j]
+        };
+        let (start, mut end) = (first.start, first.end);
+
+        // gather every change from either side that touches the same base lines
+        let mut our_group = Vec::new();
+        let mut their_group = Vec::new();
+        loop {
+            if let Some(change) = our_changes
+                .get(i)
+                .filter(|change| change.start < end || change.start == start)
+            {
+                end = end.max(change.end);
+                our_group.push(change);
+                i += 1;
+            } else if let Some(change) = their_changes
+                .get(j)
+                .filter(|change| change.start < end || change.start == start)
+            {
+                end = end.max(change.end);
+                their_group.push(change);
+                j += 1;
+            } else {
+                break;
+            }
+        }
+
+        merged.push_str(&base_lines[position..start].concat());
+        let our_text = apply_merge_changes(&base_lines, &our_group, start, end);
+        let their_text = apply_merge_changes(&base_lines, &their_group, start, end);
+        if our_group.is_empty() || our_text == their_text {
+            merged.push_str(&their_text);
+        } else if their_group.is_empty() {
+            merged.push_str(&our_text);
+        } else {
+            has_conflicts = true;
+            merged.push_str("<<<<<<< your edit\n");
+            merged.push_str(&our_text);
+            if !our_text.is_empty() && !our_text.ends_with('\n') {
+                merged.push('\n');
+            }
+            merged.push_str("=======\n");
+            merged.push_str(&their_text);
+            if !their_text.is_empty() && !their_text.ends_with('\n') {
+                merged.push('\n');
+            }
+            merged.push_str(">>>>>>> latest revision\n");
+        }
+        position = end;
+    }
+    merged.push_str(&base_lines[position..].concat());
+
+    (merged, has_conflicts)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum DeltaOp {
+    /// Copy a run of lines (start, length) from the base text.
+    Copy(usize, usize),
+    Insert(String),
+}
+
+/// Encodes `target` as line edits against `base`, so that an older revision can be stored
+/// as a delta against the newer one.
+pub fn encode_delta(base: &str, target: &str) -> String {
+    let base_lines: Vec<&str> = base.split_inclusive('\n').collect();
+    let target_lines: Vec<&str> = target.split_inclusive('\n').collect();
+
+    let mut delta = Vec::new();
+    for op in capture_diff_slices(Algorithm::Myers, &base_lines, &target_lines) {
+        match op {
+            DiffOp::Equal { old_index, len, .. } => delta.push(DeltaOp::Copy(old_index, len)),
+            DiffOp::Delete { .. } => {}
+            DiffOp::Insert {
+                new_index, new_len, ..
+            }
+            | DiffOp::Replace {
+                new_index, new_len, ..
+            } => delta.push(DeltaOp::Insert(
+                target_lines[new_index..new_index + new_len].concat(),
+            )),
+        }
+    }
+    serde_json::to_string(&delta).expect("Error encoding revision delta")
+}
+
+/// Rebuilds the text that `encode_delta` was given as target.
+pub fn apply_delta(base: &str, delta: &str) -> String {
+    let base_lines: Vec<&str> = base.split_inclusive('\n').collect();
+    let delta: Vec<DeltaOp> = serde_json::from_str(delta).expect("Corrupt revision delta");
+
+    let mut target = String::new();
+    for op in delta {
+        match op {
+            DeltaOp::Copy(start, len) => {
+                for line in &base_lines[start..start + len] {
+                    target.push_str(line);
+                }
+            }
+            DeltaOp::Insert(text) => target.push_str(&text),
+        }
+    }
+    target
+}
+
 pub fn page2raw(
     title: &String,
     markdown_content: &String,
@@ -80,3 +457,144 @@ pub fn page2raw(
 
     to_return
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_delta_round_trip(base: &str, target: &str) {
+        let delta = encode_delta(base, target);
+        assert_eq!(apply_delta(base, &delta), target, "delta {}", delta);
+    }
+
+    #[test]
+    fn delta_round_trips_unchanged_text() {
+        assert_delta_round_trip("one\ntwo\nthree\n", "one\ntwo\nthree\n");
+        assert_delta_round_trip("", "");
+    }
+
+    #[test]
+    fn delta_round_trips_from_and_to_empty_text() {
+        assert_delta_round_trip("", "one\ntwo\n");
+        assert_delta_round_trip("one\ntwo\n", "");
+    }
+
+    #[test]
+    fn delta_round_trips_edits_at_the_start_and_end() {
+        assert_delta_round_trip("one\ntwo\nthree\n", "zero\none\ntwo\nthree\n");
+        assert_delta_round_trip("one\ntwo\nthree\n", "one\ntwo\nthree\nfour\n");
+        assert_delta_round_trip("one\ntwo\nthree\n", "two\nthree\n");
+        assert_delta_round_trip("one\ntwo\nthree\n", "one\ntwo\n");
+    }
+
+    #[test]
+    fn delta_round_trips_replaced_and_moved_lines() {
+        assert_delta_round_trip("one\ntwo\nthree\nfour\n", "one\nTWO\nthree\nfour\n");
+        assert_delta_round_trip("one\ntwo\nthree\nfour\n", "three\nfour\none\ntwo\n");
+        assert_delta_round_trip("a\nb\na\nb\n", "b\na\nb\na\n");
+    }
+
+    #[test]
+    fn delta_round_trips_missing_final_newline_and_crlf() {
+        assert_delta_round_trip("one\ntwo", "one\ntwo\n");
+        assert_delta_round_trip("one\ntwo\n", "one\ntwo");
+        assert_delta_round_trip("one\r\ntwo\r\n", "one\r\nzwei\r\ndrei");
+    }
+
+    #[test]
+    fn delta_round_trips_unicode() {
+        assert_delta_round_trip("größe\n日本語\n", "größe\n日本語\n🙂\n");
+    }
+
+    #[test]
+    fn delta_only_stores_the_changed_lines() {
+        let base = "a fairly long line that should be copied, not stored again\n".repeat(20);
+        let target = format!("{}one more line\n", base);
+        assert!(encode_delta(&base, &target).len() < base.len() / 10);
+    }
+
+    #[test]
+    fn merge_without_edits_keeps_the_text() {
+        let base = "one\ntwo\nthree\n";
+        assert_eq!(merge3(base, base, base), (base.to_string(), false));
+        assert_eq!(merge3("", "", ""), (String::new(), false));
+    }
+
+    #[test]
+    fn merge_takes_the_only_side_that_changed() {
+        let base = "one\ntwo\nthree\n";
+        let edited = "one\nTWO\nthree\nfour\n";
+        assert_eq!(merge3(base, edited, base), (edited.to_string(), false));
+        assert_eq!(merge3(base, base, edited), (edited.to_string(), false));
+    }
+
+    #[test]
+    fn merge_keeps_the_same_change_once() {
+        let base = "one\ntwo\nthree\n";
+        let edited = "one\nTWO\nthree\nfour\n";
+        assert_eq!(merge3(base, edited, edited), (edited.to_string(), false));
+        assert_eq!(
+            merge3(base, "one\nthree\n", "one\nthree\n"),
+            ("one\nthree\n".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn merge_combines_edits_to_neighbouring_lines() {
+        let base = "one\ntwo\nthree\n";
+        assert_eq!(
+            merge3(base, "ONE\ntwo\nthree\n", "one\nTWO\nthree\n"),
+            ("ONE\nTWO\nthree\n".to_string(), false)
+        );
+        assert_eq!(
+            merge3(base, "ONE\ntwo\nthree\n", "one\nnew\ntwo\nthree\n"),
+            ("ONE\nnew\ntwo\nthree\n".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn merge_marks_overlapping_edits_as_one_conflict() {
+        let base = "one\ntwo\nthree\nfour\n";
+        assert_eq!(
+            merge3(base, "one\nA\nB\nfour\n", "one\ntwo\nC\nD\n"),
+            (
+                "one\n<<<<<<< your edit\nA\nB\nfour\n=======\ntwo\nC\nD\n>>>>>>> latest revision\n"
+                    .to_string(),
+                true
+            )
+        );
+        assert_eq!(
+            merge3(base, "one\nthree\nfour\n", "one\nTWO\nthree\nfour\n"),
+            (
+                "one\n<<<<<<< your edit\n=======\nTWO\n>>>>>>> latest revision\nthree\nfour\n"
+                    .to_string(),
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn merge_handles_edits_at_the_start_and_end() {
+        let base = "one\ntwo\nthree\n";
+        assert_eq!(
+            merge3(base, "zero\none\ntwo\nthree\n", "one\ntwo\nthree\nfour\n"),
+            ("zero\none\ntwo\nthree\nfour\n".to_string(), false)
+        );
+        assert_eq!(
+            merge3(base, "ONE\ntwo\nthree\n", "one\ntwo\nTHREE\n"),
+            ("ONE\ntwo\nTHREE\n".to_string(), false)
+        );
+        assert_eq!(
+            merge3(base, "one\ntwo\nthree\nours\n", "one\ntwo\nthree\ntheirs\n"),
+            (
+                "one\ntwo\nthree\n<<<<<<< your edit\nours\n=======\ntheirs\n>>>>>>> latest revision\n"
+                    .to_string(),
+                true
+            )
+        );
+        assert_eq!(
+            merge3("one\ntwo", "one\nTWO", "ONE\ntwo"),
+            ("ONE\nTWO".to_string(), false)
+        );
+    }
+}
@@ -4,9 +4,10 @@ extern crate diesel;
 extern crate rocket;
 use crate::{
     models::{Admin, AuthenticatedAdmin},
-    schema, PersistDatabase,
+    schema,
+    util::hash_password,
+    PersistDatabase,
 };
-use crypto::{digest::Digest, sha3::Sha3};
 use diesel::prelude::*;
 
 use image::{
@@ -113,6 +114,7 @@ pub async fn authenticate(
     }
     Either::Right(Redirect::to(uri!(crate::views::pages::get_page(
         "",
+        None::<i32>,
         None::<usize>
     ))))
 }
@@ -122,12 +124,6 @@ pub fn deauth(cookies: &CookieJar<'_>) -> () {
     cookies.remove_private(Cookie::named("user_id"));
 }
 
-fn hash_password(password: &String) -> String {
-    let mut hasher = Sha3::sha3_256();
-    hasher.input_str(password);
-    hasher.result_str()
-}
-
 #[post("/upload/image", data = "<form>")]
 pub async fn upload_image(
     mut form: Form<Upload<'_>>,
@@ -156,7 +152,13 @@ pub fn upload_image_form(_admin: AuthenticatedAdmin) -> Template {
 /// Admin panel, exposing misc. admin-only functionality.
 #[get("/admins/panel")]
 pub fn admin_panel(_admin: AuthenticatedAdmin) -> Template {
-    let admin_url_spec = vec![("/upload/image", "Upload Image")];
+    let admin_url_spec = vec![
+        ("/upload/image", "Upload Image"),
+        ("/admins/comments", "Moderate Comments"),
+        ("/admins/comments/rejected", "Rejected Comments"),
+        ("/admins/retention", "Revision Retention"),
+        ("/admins/trash", "Trash"),
+    ];
 
     Template::render("url_list", context! {url_spec: admin_url_spec})
 }
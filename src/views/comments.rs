@@ -1,44 +1,123 @@
-use self::models::PageRevision;
-use diesel::sql_types::{BigInt, Integer, Text};
-use image::imageops::{BiLevel, ColorMap};
-use image::io::Reader;
-use rocket::fs::NamedFile;
-use rocket::http::{CookieJar, Status};
 use rocket::response::Redirect;
-use slugify::slugify;
+
 extern crate diesel;
 extern crate rocket;
-use crate::models::{self, AuthenticatedAdmin};
-use crate::ManagedState;
-use crate::{schema, MemoryDatabase, PersistDatabase};
-use chrono::prelude::*;
-use diesel::prelude::*;
-use diesel::row::Row;
-use diesel::sql_types::Nullable;
-use diesel::sqlite::{Sqlite, SqliteConnection};
-use diesel::{prelude::*, sql_query};
-use dotenvy::dotenv;
-use image::imageops::colorops::dither;
-use image::ImageFormat;
-use image::RgbImage;
-use image::{open, DynamicImage};
+use crate::{
+    antispam,
+    models::{self, AuthenticatedAdmin, Comment, CommentStatus, RejectedComment},
+    ManagedState, PersistDatabase,
+};
+
 use models::Page;
-use pandoc::{PandocOption, PandocOutput};
-use pulldown_cmark::{html, Options, Parser};
-use rocket::form::Form;
-use rocket::fs::TempFile;
-use rocket::response::{status::Created, Debug};
-use rocket::serde::{json::Json, Deserialize, Serialize};
-use rocket::uri;
-use rocket::State;
-use rocket::{delete, get, post, put, FromForm};
+
+use rocket::{
+    form::Form,
+    get, post,
+    serde::{Deserialize, Serialize},
+    uri, Either, FromForm, State,
+};
+use chrono::Utc;
 use rocket_dyn_templates::{context, Template};
-use slab_tree::*;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Cursor;
-use std::io::{self, Write};
-use std::path::{Path, PathBuf};
-use tempdir::TempDir;
-
-type Result<T, E = Debug<diesel::result::Error>> = std::result::Result<T, E>;
+use std::{net::IpAddr, path::PathBuf};
+
+#[derive(Serialize, Deserialize, FromForm, Clone)]
+pub struct CommentInfo {
+    pub alias: String,
+    pub secret: String,
+    pub text: String,
+    pub parent_id: Option<i32>,
+    /// Honeypot, hidden from people and expected to stay empty.
+    pub website: String,
+    /// Challenge issued with the form; see `antispam::ChallengeSigner`.
+    pub challenge: String,
+    /// Proof-of-work answer computed in the browser, if enabled.
+    pub nonce: String,
+}
+
+#[derive(FromForm)]
+pub struct ModerationInfo {
+    pub ids: Vec<i32>,
+    pub action: CommentStatus,
+    pub status: CommentStatus,
+}
+
+#[post("/comment/pages/<path..>", data = "<comment_info>")]
+pub async fn create_comment(
+    state: &State<ManagedState>,
+    comment_info: Form<CommentInfo>,
+    path: PathBuf,
+    ip: Option<IpAddr>,
+    connection: PersistDatabase,
+) -> Option<Either<Template, Redirect>> {
+    let page = Page::lookup(&path, &connection).await.ok()?;
+
+    let comment_info = comment_info.into_inner();
+
+    let checked =
+        antispam::check_submission(state, ip, &comment_info, Utc::now().timestamp_millis());
+
+    let created = match checked {
+        Ok(()) => {
+            Comment::create_and_insert(page.id.unwrap(), comment_info.clone(), state, &connection)
+                .await
+        }
+        Err(rejection) => Err(rejection),
+    };
+
+    match created {
+        Ok(()) => Some(Either::Right(Redirect::to(uri!(
+            crate::views::pages::get_page(path, None::<i32>, None::<usize>)
+        )))),
+        Err(rejection) => {
+            RejectedComment::log(&connection, page.id.unwrap(), ip, &comment_info, rejection)
+                .await;
+            Some(Either::Left(Template::render(
+                "comment_rejected",
+                context! {reason: rejection.reason(), path: path},
+            )))
+        }
+    }
+}
+
+/// Moderation queue, listing the comments with the given status (pending by default).
+#[get("/admins/comments?<status>")]
+pub async fn moderation_queue(
+    status: Option<CommentStatus>,
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+) -> Template {
+    let status = status.unwrap_or(CommentStatus::Pending);
+    let comments = Comment::moderation_queue(&connection, status).await;
+
+    Template::render(
+        "comment_queue",
+        context! {comments: comments, status: status.as_str()},
+    )
+}
+
+#[post("/admins/comments", data = "<moderation_info>")]
+pub async fn moderate_comments(
+    moderation_info: Form<ModerationInfo>,
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+) -> Redirect {
+    let moderation_info = moderation_info.into_inner();
+
+    Comment::set_status(&connection, moderation_info.ids, moderation_info.action).await;
+
+    Redirect::to(format!(
+        "/admins/comments?status={}",
+        moderation_info.status.as_str()
+    ))
+}
+
+/// Log of comment submissions turned away by the anti-spam checks.
+#[get("/admins/comments/rejected")]
+pub async fn rejected_comments(
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+) -> Template {
+    let rejections = RejectedComment::recent(&connection).await;
+
+    Template::render("rejected_comments", context! {rejections: rejections})
+}
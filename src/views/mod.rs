@@ -1,6 +1,9 @@
 pub mod admins;
+pub mod comments;
 pub mod pages;
+pub mod retention;
 pub mod search;
+pub mod trash;
 
 use diesel::prelude::*;
 
@@ -16,6 +19,7 @@ pub async fn files(file: PathBuf) -> Option<NamedFile> {
 pub async fn page_redirect() -> Redirect {
     Redirect::to(uri!(crate::views::pages::get_page(
         PathBuf::from(""),
+        None::<i32>,
         None::<usize>
     )))
 }
@@ -1,5 +1,5 @@
 use self::models::PageRevision;
-use crate::util::page2raw;
+use crate::util::{diff_lines, page2raw, parse_local_datetime, CHILD_INDEX_PLACEHOLDER};
 use diesel::sql_types::{BigInt, Integer, Text};
 
 use rocket::{http::CookieJar, response::Redirect};
@@ -11,9 +11,10 @@ use crate::{
     schema, ManagedState, MemoryDatabase, PersistDatabase,
 };
 
+use chrono::Utc;
 use diesel::{prelude::*, row::Row, sql_query, sql_types::Nullable};
 
-use models::Page;
+use models::{ChildSort, Comment, Page};
 use pandoc::{PandocOption, PandocOutput};
 
 use rocket::{
@@ -21,7 +22,7 @@ use rocket::{
     get, post,
     response::Debug,
     serde::{Deserialize, Serialize},
-    uri, FromForm, State,
+    uri, Either, FromForm, State,
 };
 use rocket_dyn_templates::{context, Template};
 use slab_tree::*;
@@ -35,6 +36,27 @@ pub struct PageInfo {
     pub slug: String,
     pub markdown_content: String,
     pub sidebar_markdown_content: String,
+    pub summary: String,
+    pub is_minor: bool,
+    pub is_draft: bool,
+    /// `datetime-local` value to publish the draft at, in the display timezone.
+    pub publish_at: Option<String>,
+    /// The revision the edit form was filled from, to catch edits made in the meantime.
+    pub base_revision: Option<i32>,
+    pub show_child_index: bool,
+}
+
+#[derive(FromForm)]
+pub struct OrderInfo {
+    pub child_sort: ChildSort,
+    /// Manual positions of the children, by page id.
+    pub positions: HashMap<i32, i32>,
+}
+
+#[derive(FromForm)]
+pub struct MoveInfo {
+    /// Path of the page to move under, blank for the root.
+    pub parent_path: String,
 }
 
 #[post("/pages/<path..>", data = "<child_page>")]
@@ -42,30 +64,44 @@ pub async fn create_child_page(
     state: &State<ManagedState>,
     child_page: Form<PageInfo>,
     path: PathBuf,
-    _admin: AuthenticatedAdmin,
+    admin: AuthenticatedAdmin,
     connection: PersistDatabase,
     memory_connection: MemoryDatabase,
-) -> Redirect {
+) -> Option<Either<Template, Redirect>> {
     use models::Page;
 
-    let parent = Page::from_path(&path, &connection).await;
+    let parent = Page::lookup(&path, &connection).await.ok()?;
 
-    let child_page = child_page.into_inner();
+    let mut child_page = child_page.into_inner();
+
+    match Page::validate_slug(&connection, parent.id, None, &child_page).await {
+        Ok(slug) => child_page.slug = slug,
+        Err(error) => {
+            return Some(Either::Left(Template::render(
+                "create_child_page_form",
+                context! {path: path, page_info: child_page, error: error},
+            )))
+        }
+    }
 
     let mut child_path = path.clone();
     child_path.push(&child_page.slug);
 
     Page::create_child_and_insert(
         parent.id,
-        path.clone(),
         child_page,
+        Some(admin.id),
         state,
         &connection,
         &memory_connection,
     )
     .await;
 
-    Redirect::to(uri!(get_page(child_path, None::<usize>)))
+    Some(Either::Right(Redirect::to(uri!(get_page(
+        child_path,
+        None::<i32>,
+        None::<usize>
+    )))))
 }
 
 #[get("/create/pages/<path..>")]
@@ -73,59 +109,201 @@ pub fn create_child_page_form(path: PathBuf, _admin: AuthenticatedAdmin) -> Temp
     Template::render("create_child_page_form", context! {path: path})
 }
 
-#[get("/download/pages/<path..>?<revision>")]
+/// `revision` is the old positional index, kept so that links made before revisions had
+/// stable ids redirect to the revision they meant.
+#[get("/download/pages/<path..>?<rev>&<revision>")]
 pub async fn download_page_markdown(
     path: PathBuf,
+    rev: Option<i32>,
     revision: Option<usize>,
+    jar: &CookieJar<'_>,
     connection: PersistDatabase,
-) -> String {
-    let page = Page::from_path(&path, &connection).await;
-    let nth_rev = PageRevision::get_nth_revision(&connection, page.id.unwrap(), revision).await;
+) -> Option<Either<String, Redirect>> {
+    let page = match Page::lookup(&path, &connection).await {
+        Ok(page) => page,
+        Err(moved) => {
+            return moved.map(|moved| {
+                Either::Right(Redirect::moved(uri!(download_page_markdown(
+                    moved, rev, revision
+                ))))
+            })
+        }
+    };
 
-    page2raw(
+    if let Some(position) = revision {
+        let rev_id =
+            PageRevision::nth_revision_id(&connection, page.id.unwrap(), position).await?;
+        return Some(Either::Right(Redirect::moved(uri!(download_page_markdown(
+            path,
+            Some(rev_id),
+            None::<usize>
+        )))));
+    }
+
+    let page_revision = PageRevision::get_revision(&connection, page.id.unwrap(), rev).await?;
+
+    // drafts are not there for visitors
+    if page_revision.is_draft && jar.get_private("user_id").is_none() {
+        return None;
+    }
+
+    Some(Either::Left(page2raw(
         &page.title.clone(),
-        &nth_rev.markdown_content.clone(),
-        &nth_rev.sidebar_markdown_content.clone(),
-    )
+        &page_revision.markdown_content.clone(),
+        &page_revision.sidebar_markdown_content.clone(),
+    )))
 }
 
-#[get("/pages/<path..>?<revision>")]
+/// `revision` is the old positional index, kept so that links made before revisions had
+/// stable ids redirect to the revision they meant. A draft's `rev` is its preview, which
+/// only admins can see.
+#[get("/pages/<path..>?<rev>&<revision>")]
 pub async fn get_page(
+    state: &State<ManagedState>,
     path: PathBuf,
+    rev: Option<i32>,
     revision: Option<usize>,
     jar: &CookieJar<'_>,
     connection: PersistDatabase,
-) -> Template {
+) -> Option<Either<Template, Redirect>> {
     use self::models::PageRevision;
 
-    let page = Page::from_path(&path, &connection).await;
+    let page = match Page::lookup(&path, &connection).await {
+        Ok(page) => page,
+        Err(moved) => {
+            return moved
+                .map(|moved| Either::Right(Redirect::moved(uri!(get_page(moved, rev, revision)))))
+        }
+    };
 
-    let nav_element = Page::build_nav_element(&connection, &path).await;
+    if let Some(position) = revision {
+        let rev_id =
+            PageRevision::nth_revision_id(&connection, page.id.unwrap(), position).await?;
+        return Some(Either::Right(Redirect::moved(uri!(get_page(
+            path,
+            Some(rev_id),
+            None::<usize>
+        )))));
+    }
+
+    let nav_element = state.nav.render(&path);
 
     let is_user = match jar.get_private("user_id") {
         Some(_other_id) => true,
         None => false,
     };
 
-    let all_revisions = connection
-        .run(move |c| {
-            use crate::schema::page_revision::dsl::*;
-            page_revision
-                .filter(crate::schema::page_revision::dsl::page_id.eq(page.id))
-                .order(unix_time)
-                .load::<PageRevision>(c)
-                .expect("Database error finding page revision")
-        })
-        .await;
+    let all_revisions = PageRevision::list_for_page(&connection, page.id.unwrap(), is_user).await;
+
+    let is_latest = PageRevision::is_latest(&connection, rev, page.id.unwrap()).await;
+
+    let page_revision = PageRevision::get_revision(&connection, page.id.unwrap(), rev).await?;
+
+    if page_revision.is_draft && !is_user {
+        return None;
+    }
+
+    // the content around the child index placeholder, if the markdown has one
+    let placeholder_html = format!("<p>{}</p>", CHILD_INDEX_PLACEHOLDER);
+    let content_parts: Vec<String> = page_revision
+        .html_content
+        .splitn(2, placeholder_html.as_str())
+        .map(|part| part.to_string())
+        .collect();
+    let child_index = match page.show_child_index || content_parts.len() > 1 {
+        true => page.child_index(&connection).await,
+        false => Vec::new(),
+    };
 
-    let is_latest = PageRevision::is_latest(&connection, revision, page.id.unwrap()).await;
+    let comments = Comment::for_page(&connection, page.id.unwrap()).await;
 
-    let nth_rev = PageRevision::get_nth_revision(&connection, page.id.unwrap(), revision).await;
+    let comment_challenge = state.comment_challenges.issue(Utc::now().timestamp_millis());
 
-    Template::render(
+    Some(Either::Left(Template::render(
         "page",
-        context! {page: &page, page_revision: nth_rev, all_revisions: all_revisions, nav: &nav_element, is_user: is_user, path: path, is_latest: is_latest, revision_number: revision},
-    )
+        context! {page: &page, page_revision: page_revision, content_parts: content_parts, child_index: child_index, all_revisions: all_revisions, nav: &nav_element, is_user: is_user, path: path, is_latest: is_latest, comments: comments, comment_max_depth: state.comment_max_depth, comment_challenge: comment_challenge, comment_pow_difficulty: state.comment_pow_difficulty},
+    )))
+}
+
+/// Line- and word-level diff between two revisions of a page, by default the latest one
+/// against its predecessor. Drafts are only diffed for admins.
+#[get("/diff/pages/<path..>?<from>&<to>")]
+pub async fn diff_page(
+    path: PathBuf,
+    from: Option<i32>,
+    to: Option<i32>,
+    state: &State<ManagedState>,
+    jar: &CookieJar<'_>,
+    connection: PersistDatabase,
+) -> Option<Either<Template, Redirect>> {
+    let page = match Page::lookup(&path, &connection).await {
+        Ok(page) => page,
+        Err(moved) => {
+            return moved
+                .map(|moved| Either::Right(Redirect::moved(uri!(diff_page(moved, from, to)))))
+        }
+    };
+
+    let nav_element = state.nav.render(&path);
+
+    let is_user = jar.get_private("user_id").is_some();
+
+    let all_revisions: Vec<PageRevision> = PageRevision::load_all(&connection, page.id.unwrap())
+        .await
+        .into_iter()
+        .filter(|revision| is_user || !revision.is_draft)
+        .collect();
+
+    let to_index = match to {
+        Some(to) => all_revisions
+            .iter()
+            .position(|revision| revision.id == Some(to))?,
+        None => all_revisions.len().checked_sub(1)?,
+    };
+    let from_index = match from {
+        Some(from) => all_revisions
+            .iter()
+            .position(|revision| revision.id == Some(from))?,
+        None => to_index.saturating_sub(1),
+    };
+
+    let from_revision = &all_revisions[from_index];
+    let to_revision = &all_revisions[to_index];
+
+    let markdown_diff = diff_lines(&from_revision.markdown_content, &to_revision.markdown_content);
+    let sidebar_markdown_diff = diff_lines(
+        &from_revision.sidebar_markdown_content,
+        &to_revision.sidebar_markdown_content,
+    );
+
+    Some(Either::Left(Template::render(
+        "page_diff",
+        context! {page: &page, path: path, nav: &nav_element, from_revision: from_revision, to_revision: to_revision, markdown_diff: markdown_diff, sidebar_markdown_diff: sidebar_markdown_diff},
+    )))
+}
+
+/// Annotates each line of the current markdown with the revision that last changed it.
+#[get("/blame/pages/<path..>")]
+pub async fn blame_page(
+    path: PathBuf,
+    state: &State<ManagedState>,
+    connection: PersistDatabase,
+) -> Option<Either<Template, Redirect>> {
+    let page = match Page::lookup(&path, &connection).await {
+        Ok(page) => page,
+        Err(moved) => {
+            return moved.map(|moved| Either::Right(Redirect::moved(uri!(blame_page(moved)))))
+        }
+    };
+
+    let nav_element = state.nav.render(&path);
+
+    let blame = PageRevision::blame(&connection, page.id.unwrap()).await;
+
+    Some(Either::Left(Template::render(
+        "page_blame",
+        context! {page: &page, path: path, nav: &nav_element, blame: blame},
+    )))
 }
 
 #[post("/edit/pages/<path..>", data = "<new_page>")]
@@ -133,20 +311,71 @@ pub async fn edit_page(
     state: &State<ManagedState>,
     new_page: Form<PageInfo>,
     path: PathBuf,
-    _admin: AuthenticatedAdmin,
+    admin: AuthenticatedAdmin,
     connection: PersistDatabase,
     memory_connection: MemoryDatabase,
-) -> Redirect {
-    Page::edit_and_update(
-        path.clone(),
-        new_page.into_inner(),
+) -> Option<Either<Template, Redirect>> {
+    let page = Page::lookup(&path, &connection).await.ok()?;
+
+    let mut new_page = new_page.into_inner();
+
+    match Page::validate_slug(&connection, page.parent_id, page.id, &new_page).await {
+        Ok(slug) => new_page.slug = slug,
+        Err(error) => {
+            return Some(Either::Left(Template::render(
+                "edit_page_invalid",
+                context! {page_info: new_page, path: path, error: error},
+            )))
+        }
+    }
+
+    // a blank publish time leaves the draft unscheduled, but a mistyped one is not ignored
+    new_page.publish_at = new_page.publish_at.filter(|value| value.trim() != "");
+    let valid_publish_at = match new_page.publish_at.as_deref() {
+        Some(value) => parse_local_datetime(value, state.display_timezone).is_some(),
+        None => true,
+    };
+    if !valid_publish_at {
+        return Some(Either::Left(Template::render(
+            "edit_page_invalid",
+            context! {page_info: new_page, path: path, error: "The publish time is not a valid date and time."},
+        )));
+    }
+
+    // drafts only hold content; the page itself changes with published edits
+    let is_draft = new_page.is_draft || new_page.publish_at.is_some();
+    if is_draft
+        && (new_page.title != page.title
+            || new_page.slug != page.slug
+            || new_page.show_child_index != page.show_child_index)
+    {
+        return Some(Either::Left(Template::render(
+            "edit_page_invalid",
+            context! {page_info: new_page, path: path, error: "Drafts cannot change the title, slug or child page list; save those as a published edit."},
+        )));
+    }
+
+    let edited = Page::edit_and_update(
+        page,
+        new_page,
+        Some(admin.id),
         &connection,
         &memory_connection,
         state,
     )
     .await;
 
-    Redirect::to(uri!(get_page(path, None::<usize>)))
+    match edited {
+        Ok(()) => Some(Either::Right(Redirect::to(uri!(get_page(
+            path,
+            None::<i32>,
+            None::<usize>
+        ))))),
+        Err(conflict) => Some(Either::Left(Template::render(
+            "edit_conflict",
+            context! {conflict: conflict, path: path},
+        ))),
+    }
 }
 
 #[get("/edit/pages/<path..>")]
@@ -154,38 +383,230 @@ pub async fn edit_page_form(
     _admin: AuthenticatedAdmin,
     path: PathBuf,
     connection: PersistDatabase,
-) -> Template {
-    let page = Page::from_path(&path, &connection).await;
-    let latest_revision = PageRevision::get_nth_revision(&connection, page.id.unwrap(), None).await;
+) -> Option<Either<Template, Redirect>> {
+    let page = match Page::lookup(&path, &connection).await {
+        Ok(page) => page,
+        Err(moved) => {
+            return moved.map(|moved| Either::Right(Redirect::moved(uri!(edit_page_form(moved)))))
+        }
+    };
+    let latest_revision =
+        PageRevision::get_revision(&connection, page.id.unwrap(), None).await?;
 
-    Template::render(
+    Some(Either::Left(Template::render(
         "edit_page_form",
         context! {page: page, latest_revision: latest_revision, path: path},
+    )))
+}
+
+#[get("/publish/pages/<path..>?<rev>")]
+pub async fn publish_page(
+    state: &State<ManagedState>,
+    path: PathBuf,
+    rev: i32,
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+    memory_connection: MemoryDatabase,
+) -> Option<Either<Template, Redirect>> {
+    let page = match Page::lookup(&path, &connection).await {
+        Ok(page) => page,
+        Err(moved) => {
+            return moved
+                .map(|moved| Either::Right(Redirect::moved(uri!(publish_page(moved, rev)))))
+        }
+    };
+
+    let published = PageRevision::publish(
+        &connection,
+        &memory_connection,
+        state.parser_options,
+        page.id.unwrap(),
+        rev,
+    )
+    .await;
+
+    match published {
+        Ok(()) => Some(Either::Right(Redirect::to(uri!(get_page(
+            path,
+            None::<i32>,
+            None::<usize>
+        ))))),
+        Err(conflict) => Some(Either::Left(Template::render(
+            "edit_conflict",
+            context! {conflict: conflict, path: path, draft_id: rev},
+        ))),
+    }
+}
+
+#[get("/move/pages/<path..>")]
+pub async fn move_page_form(
+    path: PathBuf,
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+) -> Option<Either<Template, Redirect>> {
+    let page = match Page::lookup(&path, &connection).await {
+        Ok(page) => page,
+        Err(moved) => {
+            return moved.map(|moved| Either::Right(Redirect::moved(uri!(move_page_form(moved)))))
+        }
+    };
+
+    Some(Either::Left(Template::render(
+        "move_page_form",
+        context! {page: page, path: path},
+    )))
+}
+
+#[post("/move/pages/<path..>", data = "<move_info>")]
+pub async fn move_page(
+    path: PathBuf,
+    move_info: Form<MoveInfo>,
+    state: &State<ManagedState>,
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+    memory_connection: MemoryDatabase,
+) -> Option<Either<Template, Redirect>> {
+    let page = Page::lookup(&path, &connection).await.ok()?;
+
+    let parent_path = PathBuf::from(move_info.parent_path.trim_matches('/'));
+    let new_parent = match Page::try_from_path(&parent_path, &connection).await {
+        Some(new_parent) => new_parent,
+        None => {
+            return Some(Either::Left(Template::render(
+                "move_page_form",
+                context! {page: page, path: path, error: "No page exists at that path."},
+            )))
+        }
+    };
+
+    let mut moved_path = parent_path.clone();
+    moved_path.push(&page.slug);
+
+    match page
+        .clone()
+        .move_to(new_parent, &connection, &memory_connection, state)
+        .await
+    {
+        Ok(()) => Some(Either::Right(Redirect::to(uri!(get_page(
+            moved_path,
+            None::<i32>,
+            None::<usize>
+        ))))),
+        Err(error) => Some(Either::Left(Template::render(
+            "move_page_form",
+            context! {page: page, path: path, error: error},
+        ))),
+    }
+}
+
+#[get("/order/pages/<path..>")]
+pub async fn order_children_form(
+    path: PathBuf,
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+) -> Option<Either<Template, Redirect>> {
+    let page = match Page::lookup(&path, &connection).await {
+        Ok(page) => page,
+        Err(moved) => {
+            return moved.map(|moved| {
+                Either::Right(Redirect::moved(uri!(order_children_form(moved))))
+            })
+        }
+    };
+
+    let mut children = page.children(&connection).await;
+    // positions are edited as numbers, so list them in that order whatever the sort mode
+    Page::sort_siblings(&mut children, ChildSort::Manual.as_str());
+
+    Some(Either::Left(Template::render(
+        "order_children_form",
+        context! {page: page, children: children, path: path},
+    )))
+}
+
+#[post("/order/pages/<path..>", data = "<order_info>")]
+pub async fn order_children(
+    path: PathBuf,
+    order_info: Form<OrderInfo>,
+    state: &State<ManagedState>,
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+) -> Option<Redirect> {
+    let page = Page::lookup(&path, &connection).await.ok()?;
+
+    let order_info = order_info.into_inner();
+    page.reorder_children(
+        &connection,
+        state,
+        order_info.child_sort,
+        order_info.positions,
     )
+    .await;
+
+    Some(Redirect::to(uri!(get_page(path, None::<i32>, None::<usize>))))
+}
+
+#[get("/revert/pages/<path..>?<rev>")]
+pub async fn revert_page(
+    path: PathBuf,
+    rev: i32,
+    admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+    memory_connection: MemoryDatabase,
+) -> Option<Redirect> {
+    let page = match Page::lookup(&path, &connection).await {
+        Ok(page) => page,
+        Err(moved) => return moved.map(|moved| Redirect::moved(uri!(revert_page(moved, rev)))),
+    };
+
+    page.restore_revision(rev, Some(admin.id), &connection, &memory_connection)
+        .await?;
+
+    Some(Redirect::to(uri!(get_page(path, None::<i32>, None::<usize>))))
 }
 
-#[get("/delete/pages/<path..>?<revision>")]
+/// `revision` is the old positional index; it only ever deletes the revision it meant,
+/// never the page.
+#[get("/delete/pages/<path..>?<rev>&<revision>")]
 pub async fn delete_page(
     path: PathBuf,
+    state: &State<ManagedState>,
     _admin: AuthenticatedAdmin,
     connection: PersistDatabase,
     memory_connection: MemoryDatabase,
+    rev: Option<i32>,
     revision: Option<usize>,
-) -> Redirect {
+) -> Option<Redirect> {
     let spath = format!("/{}", path.to_str().unwrap().to_string());
-    if spath == "/" && revision.is_none() {
-        panic!()
+    // the root is never deleted
+    if spath == "/" && rev.is_none() && revision.is_none() {
+        return None;
     }
     let mut path = path.clone();
-    let page = Page::from_path(&path, &connection)
-        .await;
+    let page = match Page::lookup(&path, &connection).await {
+        Ok(page) => page,
+        Err(moved) => {
+            return moved.map(|moved| Redirect::moved(uri!(delete_page(moved, rev, revision))))
+        }
+    };
+
+    if let Some(position) = revision {
+        let rev_id =
+            PageRevision::nth_revision_id(&connection, page.id.unwrap(), position).await?;
+        return Some(Redirect::to(uri!(delete_page(
+            path,
+            Some(rev_id),
+            None::<usize>
+        ))));
+    }
+
     // delete only the revision
-    if revision.is_some() {
-	let nth_rev = PageRevision::get_nth_revision(&connection, page.id.unwrap(), revision).await.delete(&connection).await;
+    if rev.is_some() {
+	PageRevision::get_revision(&connection, page.id.unwrap(), rev).await?.delete(&connection).await;
     } else {
 	path.pop();
-	page.delete(&connection, &memory_connection).await;
+	page.delete(&connection, &memory_connection, state).await;
     }
 
-    Redirect::to(uri!(get_page(path, None::<usize>)))
+    Some(Redirect::to(uri!(get_page(path, None::<i32>, None::<usize>))))
 }
@@ -0,0 +1,108 @@
+use rocket::response::Redirect;
+
+extern crate diesel;
+extern crate rocket;
+use crate::{
+    models::{AuthenticatedAdmin, Page, RetentionPolicy},
+    PersistDatabase,
+};
+
+use rocket::{form::Form, get, post, Either, FromForm};
+use rocket_dyn_templates::{context, Template};
+use std::path::PathBuf;
+
+#[derive(FromForm)]
+pub struct RetentionInfo {
+    /// Root of the subtree the policy covers, or blank for the global policy.
+    pub path: String,
+    pub keep_all_days: i32,
+    pub keep_weekly_days: i32,
+    /// Blank to keep monthly revisions forever.
+    pub keep_monthly_days: Option<i32>,
+}
+
+#[derive(FromForm)]
+pub struct PruneInfo {
+    pub ids: Vec<i32>,
+}
+
+#[get("/admins/retention")]
+pub async fn retention_policies(
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+) -> Template {
+    let policies = RetentionPolicy::list(&connection).await;
+
+    Template::render("retention_policies", context! {policies: policies})
+}
+
+#[post("/admins/retention", data = "<retention_info>")]
+pub async fn save_retention_policy(
+    retention_info: Form<RetentionInfo>,
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+) -> Either<Template, Redirect> {
+    let retention_info = retention_info.into_inner();
+
+    let path = retention_info.path.trim_matches('/');
+    let page_id = match path {
+        "" => None,
+        path => match Page::try_from_path(&PathBuf::from(path), &connection).await {
+            Some(page) => page.id,
+            None => {
+                let policies = RetentionPolicy::list(&connection).await;
+                return Either::Left(Template::render(
+                    "retention_policies",
+                    context! {policies: policies, error: "No page exists at that path."},
+                ));
+            }
+        },
+    };
+
+    RetentionPolicy {
+        id: None,
+        page_id: page_id,
+        keep_all_days: retention_info.keep_all_days,
+        keep_weekly_days: retention_info.keep_weekly_days,
+        keep_monthly_days: retention_info.keep_monthly_days,
+    }
+    .save(&connection)
+    .await;
+
+    Either::Right(Redirect::to("/admins/retention"))
+}
+
+#[get("/admins/retention/delete?<id>")]
+pub async fn delete_retention_policy(
+    id: i32,
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+) -> Redirect {
+    RetentionPolicy::delete(&connection, id).await;
+
+    Redirect::to("/admins/retention")
+}
+
+/// Preview of the revisions the retention policies would remove.
+#[get("/admins/retention/prune")]
+pub async fn prune_preview(_admin: AuthenticatedAdmin, connection: PersistDatabase) -> Template {
+    let prunable = RetentionPolicy::plan_prune(&connection).await;
+
+    Template::render("retention_prune", context! {prunable: prunable})
+}
+
+#[post("/admins/retention/prune", data = "<prune_info>")]
+pub async fn prune_revisions(
+    prune_info: Form<PruneInfo>,
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+) -> Template {
+    let pruned = RetentionPolicy::prune(&connection, prune_info.into_inner().ids).await;
+
+    let prunable = RetentionPolicy::plan_prune(&connection).await;
+
+    Template::render(
+        "retention_prune",
+        context! {prunable: prunable, pruned: pruned},
+    )
+}
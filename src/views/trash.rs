@@ -0,0 +1,59 @@
+use rocket::response::Redirect;
+
+extern crate diesel;
+extern crate rocket;
+use crate::{
+    models::{AuthenticatedAdmin, Page},
+    ManagedState, MemoryDatabase, PersistDatabase,
+};
+
+use rocket::{get, post, Either, State};
+use rocket_dyn_templates::{context, Template};
+
+/// Deleted subtrees, which can be restored until they are purged.
+#[get("/admins/trash")]
+pub async fn trash(
+    state: &State<ManagedState>,
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+) -> Template {
+    let trashed = Page::trash(&connection).await;
+
+    Template::render(
+        "trash",
+        context! {trashed: trashed, trash_purge_days: state.trash_purge_days},
+    )
+}
+
+#[get("/admins/trash/restore?<id>")]
+pub async fn restore_trashed_page(
+    state: &State<ManagedState>,
+    id: i32,
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+    memory_connection: MemoryDatabase,
+) -> Option<Either<Template, Redirect>> {
+    match Page::restore_from_trash(&connection, &memory_connection, state, id).await? {
+        Ok(()) => Some(Either::Right(Redirect::to("/admins/trash"))),
+        Err(error) => {
+            let trashed = Page::trash(&connection).await;
+            Some(Either::Left(Template::render(
+                "trash",
+                context! {trashed: trashed, trash_purge_days: state.trash_purge_days, error: error},
+            )))
+        }
+    }
+}
+
+#[post("/admins/trash/purge?<id>")]
+pub async fn purge_trashed_page(
+    state: &State<ManagedState>,
+    id: i32,
+    _admin: AuthenticatedAdmin,
+    connection: PersistDatabase,
+    memory_connection: MemoryDatabase,
+) -> Option<Redirect> {
+    Page::purge(&connection, &memory_connection, &state.nav, id).await?;
+
+    Some(Redirect::to("/admins/trash"))
+}